@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use reqwest::header;
+use serde::Serialize;
+
+/// `POST /api/v1/cli/add` of ArchiveBox 0.8
+const AB_ADD: &str = "api/v1/cli/add";
+
+#[derive(Serialize)]
+struct AddReq<'a> {
+    urls: [&'a str; 1],
+    depth: u8,
+}
+
+pub struct Client {
+    client: reqwest::Client,
+    add_url: String,
+}
+
+impl Client {
+    /// `base` is the root of the ArchiveBox instance, e.g. `http://localhost:8000/`
+    pub fn new(base: &str, api_key: &str) -> Self {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", api_key).parse().unwrap(),
+        );
+        let client = reqwest::Client::builder()
+            // adding blocks until the snapshot is done
+            .timeout(Duration::from_secs(120))
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let add_url = format!("{}/{}", base.trim_end_matches('/'), AB_ADD);
+        Client { client, add_url }
+    }
+
+    pub async fn save(&self, url: &str) -> anyhow::Result<()> {
+        self.client
+            .post(&self.add_url)
+            .json(&AddReq {
+                urls: [url],
+                depth: 0,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{archive_box, wayback_machine};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Config {
+    WaybackMachine { token: String },
+    ArchiveBox { url: String, api_key: String },
+}
+
+pub enum Archiver {
+    WaybackMachine(wayback_machine::Client),
+    ArchiveBox(archive_box::Client),
+}
+
+impl Archiver {
+    pub fn new(config: &Config) -> Self {
+        match config {
+            Config::WaybackMachine { token } => {
+                Archiver::WaybackMachine(wayback_machine::Client::new(token))
+            }
+            Config::ArchiveBox { url, api_key } => {
+                Archiver::ArchiveBox(archive_box::Client::new(url, api_key))
+            }
+        }
+    }
+
+    /// Whether a capture can be started right now
+    pub async fn has_capacity(&self) -> anyhow::Result<bool> {
+        match self {
+            Archiver::WaybackMachine(c) => c.has_capacity().await,
+            Archiver::ArchiveBox(_) => Ok(true),
+        }
+    }
+
+    pub async fn save(&self, url: &str) -> anyhow::Result<()> {
+        match self {
+            Archiver::WaybackMachine(c) => c.save(url).await,
+            Archiver::ArchiveBox(c) => c.save(url).await,
+        }
+    }
+}
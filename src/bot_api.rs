@@ -0,0 +1,74 @@
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::multipart::Form;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::http::CLIENT;
+
+const API: &str = "https://api.telegram.org/bot";
+/// `CLIENT` gives up after 5s, far too soon for albums of large documents
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize)]
+struct Resp {
+    ok: bool,
+    result: Option<Value>,
+    description: Option<String>,
+    error_code: Option<u16>,
+}
+
+/// Telegram refused a raw call
+#[derive(Debug)]
+pub struct Error {
+    pub method: String,
+    pub error_code: u16,
+    pub description: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}",
+            self.method, self.error_code, self.description
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Calls a Bot API method tbot doesn't have and returns its `result`
+pub async fn call_raw(token: &str, method: &str, params: &Value) -> anyhow::Result<Value> {
+    let req = CLIENT.with(|client| {
+        client
+            .post(&format!("{}{}/{}", API, token, method))
+            .json(params)
+    });
+    parse(method, req.send().await?.json().await?)
+}
+
+/// Like `call_raw`, for methods that upload files, e.g. `sendMediaGroup`
+/// with documents
+pub async fn call_raw_multipart(token: &str, method: &str, form: Form) -> anyhow::Result<Value> {
+    let req = CLIENT.with(|client| {
+        client
+            .post(&format!("{}{}/{}", API, token, method))
+            .multipart(form)
+            .timeout(UPLOAD_TIMEOUT)
+    });
+    parse(method, req.send().await?.json().await?)
+}
+
+fn parse(method: &str, resp: Resp) -> anyhow::Result<Value> {
+    if resp.ok {
+        return Ok(resp.result.unwrap_or(Value::Null));
+    }
+    Err(Error {
+        method: method.to_owned(),
+        error_code: resp.error_code.unwrap_or_default(),
+        description: resp.description.unwrap_or_default(),
+    }
+    .into())
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use tbot::errors::MethodCall;
+
+use crate::bot_api;
+
+/// Bot API failures that need someone to fix the setup or the bot, told
+/// apart by the description telegram sends along
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotError {
+    WrongFileType,
+    CaptionTooLong,
+    MessageTooLong,
+    BadMarkdown,
+    ChatNotFound,
+    NotAdmin,
+}
+
+impl BotError {
+    pub fn from_description(description: &str) -> Option<Self> {
+        let d = description.to_ascii_lowercase();
+        let kind = if d.contains("wrong file")
+            || d.contains("wrong type")
+            || d.contains("image_process_failed")
+            || d.contains("photo_invalid_dimensions")
+        {
+            BotError::WrongFileType
+        } else if d.contains("caption is too long") || d.contains("media_caption_too_long") {
+            BotError::CaptionTooLong
+        } else if d.contains("message is too long") {
+            BotError::MessageTooLong
+        } else if d.contains("can't parse entities") {
+            BotError::BadMarkdown
+        } else if d.contains("chat not found") {
+            BotError::ChatNotFound
+        } else if d.contains("administrator rights")
+            || d.contains("not enough rights")
+            || d.contains("bot is not a member")
+            || d.contains("bot was kicked")
+        {
+            BotError::NotAdmin
+        } else {
+            return None;
+        };
+        Some(kind)
+    }
+
+    /// Looks for a telegram request error anywhere in the chain
+    pub fn of(e: &anyhow::Error) -> Option<Self> {
+        e.chain().find_map(|e| {
+            if let Some(raw) = e.downcast_ref::<bot_api::Error>() {
+                return Self::from_description(&raw.description);
+            }
+            match e.downcast_ref()? {
+                MethodCall::RequestError { description, .. } => Self::from_description(description),
+                _ => None,
+            }
+        })
+    }
+
+    fn hint(self) -> &'static str {
+        match self {
+            BotError::WrongFileType => {
+                "telegram rejected the file, the image may be corrupted or in an unsupported format"
+            }
+            BotError::CaptionTooLong => "caption over 1024 characters, check the caption header/footer",
+            BotError::MessageTooLong => "message over 4096 characters, check the caption header/footer",
+            BotError::BadMarkdown => {
+                "invalid markdown, some text is missing escaping or the header/footer template is broken"
+            }
+            BotError::ChatNotFound => {
+                "chat not found, check channel/assets_channel in db.json and that the bot was added"
+            }
+            BotError::NotAdmin => "the bot needs to be an admin allowed to post in the channel",
+        }
+    }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.hint())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(
+            BotError::from_description("Bad Request: chat not found"),
+            Some(BotError::ChatNotFound)
+        );
+        assert_eq!(
+            BotError::from_description("Bad Request: message caption is too long"),
+            Some(BotError::CaptionTooLong)
+        );
+        assert_eq!(
+            BotError::from_description(
+                "Bad Request: can't parse entities: Can't find end of the entity starting at byte offset 12"
+            ),
+            Some(BotError::BadMarkdown)
+        );
+        assert_eq!(
+            BotError::from_description("Forbidden: bot is not a member of the channel chat"),
+            Some(BotError::NotAdmin)
+        );
+        assert_eq!(
+            BotError::from_description("Bad Request: message to edit not found"),
+            None
+        );
+    }
+}
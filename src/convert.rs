@@ -1,309 +1,196 @@
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::ops::Deref;
-use std::sync::{Arc, Mutex};
-
-use anyhow::{bail, Context, Result};
-use rsmpeg::avcodec::{AVCodec, AVCodecContext};
-use rsmpeg::avformat::{
-    AVFormatContextInput, AVFormatContextOutput, AVIOContextContainer, AVIOContextCustom,
-};
-use rsmpeg::avutil::{AVFrame, AVMem};
-use rsmpeg::error::RsmpegError;
-use rsmpeg::ffi;
-use rsmpeg::swscale::SwsContext;
-
-struct AVFrameIter {
-    frame_buffer: AVFrame,
-    format_context: AVFormatContextInput,
-    decode_context: AVCodecContext,
-    stream_index: usize,
+#[cfg(any(feature = "ffmpeg", test))]
+use std::ops::Range;
+
+#[cfg(any(feature = "ffmpeg", test))]
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ffmpeg")]
+mod ffmpeg;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Options {
+    /// Encoder threads, 0 lets libx264 use every core. Run the bot under
+    /// `nice` (or systemd's `Nice=`) to lower the priority further.
+    pub threads: usize,
+    /// Longer inputs are cut short, see `Mp4::truncated`
+    pub max_frames: Option<usize>,
+    /// In seconds
+    pub max_duration: Option<f64>,
+    /// Burn the post's votes into a corner of the video
+    pub overlay_votes: bool,
+    /// Font for the overlay, fontconfig's default if unset
+    pub overlay_font: Option<String>,
+    /// Send the original GIF as an animation when it's smaller than the
+    /// MP4, or when converting it fails. Not for GIFs in albums, or when
+    /// burning in votes.
+    pub keep_smaller_gif: bool,
 }
 
-impl AVFrameIter {
-    fn next_frame(&mut self) -> Result<Option<&mut AVFrame>> {
-        loop {
-            let packet = loop {
-                match self.format_context.read_packet()? {
-                    Some(x) if x.stream_index != self.stream_index as i32 => {}
-                    x => break x,
-                }
-            };
-
-            match self.decode_context.send_packet(packet.as_ref()) {
-                Ok(_) | Err(RsmpegError::DecoderFlushedError) => {}
-                Err(e) => return Err(e.into()),
-            };
+pub struct Mp4 {
+    pub data: Vec<u8>,
+    /// The input exceeded `max_frames` or `max_duration`
+    pub truncated: bool,
+}
 
-            match self.decode_context.receive_frame() {
-                Ok(frame) => {
-                    self.frame_buffer = frame;
+/// `(type, range, header length)`
+#[cfg(any(feature = "ffmpeg", test))]
+type Atom = ([u8; 4], Range<usize>, usize);
+
+/// Returns every atom in `data`.
+#[cfg(any(feature = "ffmpeg", test))]
+fn atoms(data: &[u8]) -> Result<Vec<Atom>> {
+    let mut r = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).context("Truncated atom header")?;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let (size, header_len) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => ((data.len() - pos) as u64, 8),
+            1 => {
+                let large = data
+                    .get(pos + 8..pos + 16)
+                    .context("Truncated atom header")?;
+                (u64::from_be_bytes(large.try_into().unwrap()), 16)
+            }
+            n => (n as u64, 8),
+        };
+        let end = usize::try_from(size)
+            .ok()
+            .filter(|&size| size >= header_len)
+            .and_then(|size| pos.checked_add(size))
+            .filter(|&end| end <= data.len())
+            .context("Invalid atom size")?;
+        r.push((kind, pos..end, header_len));
+        pos = end;
+    }
+    Ok(r)
+}
 
-                    break Ok(Some(&mut self.frame_buffer));
+/// Adds `shift` to every chunk offset in the children of a container atom.
+#[cfg(any(feature = "ffmpeg", test))]
+fn shift_chunk_offsets(data: &mut [u8], shift: u64) -> Result<()> {
+    for (kind, range, header_len) in atoms(data)? {
+        let atom = &mut data[range];
+        match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                shift_chunk_offsets(&mut atom[header_len..], shift)?
+            }
+            b"stco" | b"co64" => {
+                let width = if &kind == b"co64" { 8 } else { 4 };
+                let body = &mut atom[header_len..];
+                let count = body.get(4..8).context("Truncated chunk offset atom")?;
+                let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+                let entries = body
+                    .get_mut(8..8 + count * width)
+                    .context("Truncated chunk offset atom")?;
+                for entry in entries.chunks_exact_mut(width) {
+                    if width == 8 {
+                        let offset = u64::from_be_bytes((&*entry).try_into().unwrap()) + shift;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    } else {
+                        let offset =
+                            u32::from_be_bytes((&*entry).try_into().unwrap()) as u64 + shift;
+                        let offset = u32::try_from(offset).context("Chunk offset overflow")?;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    }
                 }
-                Err(RsmpegError::DecoderDrainError) => {}
-                Err(RsmpegError::DecoderFlushedError) => break Ok(None),
-                Err(e) => break Err(e.into()),
             }
+            _ => {}
         }
     }
+    Ok(())
 }
 
-fn decode_video(input_format_context: AVFormatContextInput) -> Result<AVFrameIter> {
-    let (stream_index, decode_context) = {
-        let (stream_index, decoder) = input_format_context
-            .find_best_stream(ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)?
-            .context("Failed to find the best stream")?;
-        let stream = input_format_context.streams().get(stream_index).unwrap();
-
-        let mut decode_context = AVCodecContext::new(&decoder);
-        decode_context.apply_codecpar(&stream.codecpar())?;
-        decode_context.open(None)?;
-        decode_context.set_framerate(stream.avg_frame_rate);
-        decode_context.set_time_base(stream.time_base);
-
-        (stream_index, decode_context)
-    };
-
-    Ok(AVFrameIter {
-        frame_buffer: AVFrame::new(),
-        format_context: input_format_context,
-        decode_context,
-        stream_index,
-    })
-}
-
-#[allow(clippy::type_complexity)]
-fn io_context_custom(
-    data: Vec<u8>,
-    write: bool,
-) -> Result<(AVIOContextCustom, Arc<Mutex<Cursor<Vec<u8>>>>)> {
-    let data = Arc::new(Mutex::new(Cursor::new(data)));
-
-    let seek = {
-        let data = data.clone();
-        Box::new(move |_: &mut Vec<u8>, offset: i64, whence: i32| {
-            let mut data = data.lock().unwrap();
-            const AVSEEK_SIZE: i32 = ffi::AVSEEK_SIZE as i32;
-            match whence {
-                0 => data.seek(SeekFrom::Start(offset as _)),
-                1 => data.seek(SeekFrom::Current(offset)),
-                2 => data.seek(SeekFrom::End(offset)),
-                AVSEEK_SIZE => return data.get_ref().len() as _,
-                _ => return -1,
-            }
-            .map(|x| x as _)
-            .unwrap_or(-1)
-        })
+/// Moves the `moov` atom in front of `mdat`, like `qt-faststart`, so clients
+/// can start playback before the whole file is downloaded.
+///
+/// The muxer's `movflags=faststart` reopens the output by URL to shift the
+/// data, which doesn't work with our custom IO context.
+#[cfg(any(feature = "ffmpeg", test))]
+fn faststart(mp4: Vec<u8>) -> Result<Vec<u8>> {
+    let atoms = atoms(&mp4)?;
+    let find = |name: &[u8; 4]| {
+        atoms
+            .iter()
+            .find(|(kind, _, _)| kind == name)
+            .map(|(_, range, header_len)| (range.clone(), *header_len))
     };
+    let (moov, moov_header_len) = find(b"moov").context("Failed to find moov atom")?;
+    let (mdat, _) = find(b"mdat").context("Failed to find mdat atom")?;
+    if moov.start < mdat.start {
+        return Ok(mp4);
+    }
 
-    let io_context = if write {
-        let write_packet = {
-            let data = data.clone();
-            Box::new(
-                move |_: &mut Vec<u8>, buf: &[u8]| match data.lock().unwrap().write_all(buf) {
-                    Ok(_) => buf.len() as _,
-                    Err(_) => -1,
-                },
-            )
-        };
-
-        AVIOContextCustom::alloc_context(
-            AVMem::new(4096),
-            true,
-            Vec::new(),
-            None,
-            Some(write_packet),
-            Some(seek),
-        )
-    } else {
-        let read_packet = {
-            let data = data.clone();
-            Box::new(move |_: &mut Vec<u8>, buf: &mut [u8]| {
-                let mut data = data.lock().unwrap();
-                match data.read(buf) {
-                    Ok(0) => ffi::AVERROR_EOF,
-                    Ok(n) => n as _,
-                    Err(_) => -1,
-                }
-            })
-        };
-
-        AVIOContextCustom::alloc_context(
-            AVMem::new(4096),
-            false,
-            Vec::new(),
-            Some(read_packet),
-            None,
-            Some(seek),
-        )
-    };
+    let mut moov_data = mp4[moov.clone()].to_vec();
+    shift_chunk_offsets(&mut moov_data[moov_header_len..], moov.len() as u64)?;
 
-    Ok((io_context, data))
+    let mut r = Vec::with_capacity(mp4.len());
+    r.extend_from_slice(&mp4[..mdat.start]);
+    r.extend_from_slice(&moov_data);
+    r.extend_from_slice(&mp4[mdat.start..moov.start]);
+    r.extend_from_slice(&mp4[moov.end..]);
+    Ok(r)
 }
 
-fn input_format_context(data: Vec<u8>) -> Result<AVFormatContextInput> {
-    let (io_context, _) = io_context_custom(data, false)?;
-    let input_format_context =
-        AVFormatContextInput::from_io_context(AVIOContextContainer::Custom(io_context))?;
-
-    Ok(input_format_context)
+/// Renders the first frame of a GIF as JPEG, a poster for when the
+/// conversion or the upload of the video fails.
+pub fn first_frame(data: &[u8]) -> Result<Vec<u8>> {
+    let frame = image::load_from_memory_with_format(data, image::ImageFormat::Gif)?;
+    let mut r = Vec::new();
+    image::DynamicImage::ImageRgb8(frame.to_rgb8())
+        .write_to(&mut r, image::ImageOutputFormat::Jpeg(90))?;
+    Ok(r)
 }
 
-#[allow(clippy::type_complexity)]
-fn output_format_context() -> Result<(AVFormatContextOutput, Arc<Mutex<Cursor<Vec<u8>>>>)> {
-    let (io_context, data) = io_context_custom(Vec::new(), true)?;
-    let output_format_context =
-        AVFormatContextOutput::create(c".mp4", Some(AVIOContextContainer::Custom(io_context)))?;
-
-    Ok((output_format_context, data))
+/// `overlay` is burned into the video with drawtext, it must not contain
+/// quotes.
+#[cfg(feature = "ffmpeg")]
+pub fn video_to_mp4(data: Vec<u8>, options: &Options, overlay: Option<&str>) -> Result<Mp4> {
+    let mp4 = ffmpeg::video_to_mp4(data, options, overlay)?;
+    Ok(Mp4 {
+        data: faststart(mp4.data)?,
+        ..mp4
+    })
 }
 
-fn encode_mp4(mut src: AVFrameIter) -> Result<Vec<u8>> {
-    let buffer = {
-        let time_base = src.decode_context.time_base;
-        let framerate = src.decode_context.framerate;
-        let first_frame = src.next_frame()?.context("Failed to get first frame")?;
-        let &ffi::AVFrame {
-            width: src_width,
-            height: src_height,
-            format: src_format,
-            ..
-        } = (*first_frame).deref();
-        let dst_width = src_width + src_width % 2;
-        let dst_height = src_height + src_height % 2;
-        let dst_format = ffi::AVPixelFormat_AV_PIX_FMT_YUV420P;
-
-        let (mut output_format_context, buffer) = output_format_context()?;
-
-        let encoder =
-            AVCodec::find_encoder_by_name(c"libx264").context("Failed to find encoder codec")?;
-        let mut encode_context = AVCodecContext::new(&encoder);
-        encode_context.set_width(dst_width);
-        encode_context.set_height(dst_height);
-        encode_context.set_pix_fmt(dst_format);
-        encode_context.set_time_base(time_base);
-        encode_context.set_framerate(framerate);
-        unsafe {
-            if ffi::av_opt_set(
-                encode_context.priv_data,
-                c"preset".as_ptr(),
-                c"slow".as_ptr(),
-                0,
-            ) < 0
-            {
-                bail!("Failed to set preset");
-            }
-        }
-        if output_format_context.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
-            encode_context
-                .set_flags(encode_context.flags | ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
-        }
-        encode_context.open(None)?;
-
-        let mut dst_frame = AVFrame::new();
-        dst_frame.set_width(dst_width);
-        dst_frame.set_height(dst_height);
-        dst_frame.set_format(dst_format);
-        dst_frame.alloc_buffer()?;
-
-        {
-            let mut out_stream = output_format_context.new_stream();
-            out_stream.set_codecpar(encode_context.extract_codecpar());
-        }
-
-        output_format_context.write_header(&mut None)?;
-
-        let mut sws_context =
-            if src_format != dst_frame.format || src_width != dst_width || src_height != dst_height
-            {
-                let sws_context = SwsContext::get_context(
-                    src_width,
-                    src_height,
-                    src_format,
-                    dst_width,
-                    dst_height,
-                    dst_format,
-                    ffi::SWS_FAST_BILINEAR | ffi::SWS_ACCURATE_RND,
-                )
-                .context("Failed to get sws_context")?;
-                Some(sws_context)
-            } else {
-                None
-            };
-
-        let mut encode_frame = |src_frame: &mut AVFrame| -> Result<()> {
-            let frame_after = if let Some(sws_context) = sws_context.as_mut() {
-                sws_context.scale_frame(src_frame, 0, src_height, &mut dst_frame)?;
-                dst_frame.set_pts(src_frame.pts);
-                &mut dst_frame
-            } else {
-                src_frame
-            };
-
-            encode_write_frame(
-                Some(frame_after),
-                &mut encode_context,
-                &mut output_format_context,
-                0,
-            )
-        };
-        encode_frame(first_frame)?;
-        while let Some(src_frame) = src.next_frame()? {
-            encode_frame(src_frame)?;
-        }
-
-        encode_write_frame(None, &mut encode_context, &mut output_format_context, 0)?;
-        output_format_context.write_trailer()?;
-
-        buffer
-    };
-
-    let ret = Arc::into_inner(buffer)
-        .context("Failed to get buffer")?
-        .into_inner()?
-        .into_inner();
-
-    Ok(ret)
+/// Fails every time, GIFs go out as their first frame and videos as
+/// downloaded
+#[cfg(not(feature = "ffmpeg"))]
+pub fn video_to_mp4(_: Vec<u8>, _: &Options, _: Option<&str>) -> Result<Mp4> {
+    anyhow::bail!("built without ffmpeg")
 }
 
-fn encode_write_frame(
-    frame_after: Option<&AVFrame>,
-    encode_context: &mut AVCodecContext,
-    output_format_context: &mut AVFormatContextOutput,
-    out_stream_index: usize,
-) -> Result<()> {
-    encode_context.send_frame(frame_after)?;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    loop {
-        let mut packet = match encode_context.receive_packet() {
-            Ok(packet) => packet,
-            Err(RsmpegError::EncoderDrainError) | Err(RsmpegError::EncoderFlushedError) => break,
-            Err(e) => return Err(e.into()),
-        };
-
-        packet.set_stream_index(out_stream_index as i32);
-        packet.rescale_ts(
-            encode_context.time_base,
-            output_format_context
-                .streams()
-                .get(out_stream_index)
-                .context("Failed to get stream")?
-                .time_base,
-        );
-
-        match output_format_context.interleaved_write_frame(&mut packet) {
-            Ok(()) => Ok(()),
-            Err(RsmpegError::InterleavedWriteFrameError(-22)) => Ok(()),
-            Err(e) => Err(e),
-        }?;
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut r = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        r.extend_from_slice(kind);
+        r.extend_from_slice(body);
+        r
     }
 
-    Ok(())
-}
+    fn moov(chunk_offset: u32) -> Vec<u8> {
+        let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stco.extend_from_slice(&chunk_offset.to_be_bytes());
+        let stbl = atom(b"stbl", &atom(b"stco", &stco));
+        let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+        atom(b"moov", &trak)
+    }
 
-pub fn video_to_mp4(data: Vec<u8>) -> Result<Vec<u8>> {
-    let format_context = input_format_context(data)?;
-    let frame_iter = decode_video(format_context)?;
+    #[test]
+    fn faststart_moves_moov() {
+        let ftyp = atom(b"ftyp", b"isom");
+        let mdat = atom(b"mdat", b"data");
+        let offset = (ftyp.len() + 8) as u32;
+        let input = [ftyp.clone(), mdat.clone(), moov(offset)].concat();
 
-    encode_mp4(frame_iter)
+        let moov_len = moov(0).len() as u32;
+        let expected = [ftyp, moov(offset + moov_len), mdat].concat();
+        assert_eq!(faststart(input).unwrap(), expected);
+    }
 }
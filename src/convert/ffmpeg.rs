@@ -0,0 +1,548 @@
+use std::ffi::{CStr, CString};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use rsmpeg::avcodec::{AVCodec, AVCodecContext};
+use rsmpeg::avfilter::{AVFilter, AVFilterContext, AVFilterGraph, AVFilterInOut};
+use rsmpeg::avformat::{
+    AVFormatContextInput, AVFormatContextOutput, AVIOContextContainer, AVIOContextCustom,
+};
+use rsmpeg::avutil::{AVFrame, AVMem};
+use rsmpeg::error::RsmpegError;
+use rsmpeg::ffi;
+use rsmpeg::swscale::SwsContext;
+
+use super::{Mp4, Options};
+
+struct AVFrameIter {
+    frame_buffer: AVFrame,
+    format_context: AVFormatContextInput,
+    decode_context: AVCodecContext,
+    stream_index: usize,
+}
+
+impl AVFrameIter {
+    fn next_frame(&mut self) -> Result<Option<&mut AVFrame>> {
+        loop {
+            let packet = loop {
+                match self.format_context.read_packet()? {
+                    Some(x) if x.stream_index != self.stream_index as i32 => {}
+                    x => break x,
+                }
+            };
+
+            match self.decode_context.send_packet(packet.as_ref()) {
+                Ok(_) | Err(RsmpegError::DecoderFlushedError) => {}
+                Err(e) => return Err(e.into()),
+            };
+
+            match self.decode_context.receive_frame() {
+                Ok(frame) => {
+                    self.frame_buffer = frame;
+
+                    break Ok(Some(&mut self.frame_buffer));
+                }
+                Err(RsmpegError::DecoderDrainError) => {}
+                Err(RsmpegError::DecoderFlushedError) => break Ok(None),
+                Err(e) => break Err(e.into()),
+            }
+        }
+    }
+}
+
+fn decode_video(input_format_context: AVFormatContextInput) -> Result<AVFrameIter> {
+    let (stream_index, decode_context) = {
+        let (stream_index, decoder) = input_format_context
+            .find_best_stream(ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)?
+            .context("Failed to find the best stream")?;
+        let stream = input_format_context.streams().get(stream_index).unwrap();
+
+        let mut decode_context = AVCodecContext::new(&decoder);
+        decode_context.apply_codecpar(&stream.codecpar())?;
+        decode_context.open(None)?;
+        decode_context.set_framerate(stream.avg_frame_rate);
+        decode_context.set_time_base(stream.time_base);
+
+        (stream_index, decode_context)
+    };
+
+    Ok(AVFrameIter {
+        frame_buffer: AVFrame::new(),
+        format_context: input_format_context,
+        decode_context,
+        stream_index,
+    })
+}
+
+#[allow(clippy::type_complexity)]
+fn io_context_custom(
+    data: Vec<u8>,
+    write: bool,
+) -> Result<(AVIOContextCustom, Arc<Mutex<Cursor<Vec<u8>>>>)> {
+    let data = Arc::new(Mutex::new(Cursor::new(data)));
+
+    let seek = {
+        let data = data.clone();
+        Box::new(move |_: &mut Vec<u8>, offset: i64, whence: i32| {
+            let mut data = data.lock().unwrap();
+            const AVSEEK_SIZE: i32 = ffi::AVSEEK_SIZE as i32;
+            match whence {
+                0 => data.seek(SeekFrom::Start(offset as _)),
+                1 => data.seek(SeekFrom::Current(offset)),
+                2 => data.seek(SeekFrom::End(offset)),
+                AVSEEK_SIZE => return data.get_ref().len() as _,
+                _ => return -1,
+            }
+            .map(|x| x as _)
+            .unwrap_or(-1)
+        })
+    };
+
+    let io_context = if write {
+        let write_packet = {
+            let data = data.clone();
+            Box::new(
+                move |_: &mut Vec<u8>, buf: &[u8]| match data.lock().unwrap().write_all(buf) {
+                    Ok(_) => buf.len() as _,
+                    Err(_) => -1,
+                },
+            )
+        };
+
+        AVIOContextCustom::alloc_context(
+            AVMem::new(4096),
+            true,
+            Vec::new(),
+            None,
+            Some(write_packet),
+            Some(seek),
+        )
+    } else {
+        let read_packet = {
+            let data = data.clone();
+            Box::new(move |_: &mut Vec<u8>, buf: &mut [u8]| {
+                let mut data = data.lock().unwrap();
+                match data.read(buf) {
+                    Ok(0) => ffi::AVERROR_EOF,
+                    Ok(n) => n as _,
+                    Err(_) => -1,
+                }
+            })
+        };
+
+        AVIOContextCustom::alloc_context(
+            AVMem::new(4096),
+            false,
+            Vec::new(),
+            Some(read_packet),
+            None,
+            Some(seek),
+        )
+    };
+
+    Ok((io_context, data))
+}
+
+fn input_format_context(data: Vec<u8>) -> Result<AVFormatContextInput> {
+    let (io_context, _) = io_context_custom(data, false)?;
+    let input_format_context =
+        AVFormatContextInput::from_io_context(AVIOContextContainer::Custom(io_context))?;
+
+    Ok(input_format_context)
+}
+
+#[allow(clippy::type_complexity)]
+fn output_format_context() -> Result<(AVFormatContextOutput, Arc<Mutex<Cursor<Vec<u8>>>>)> {
+    let (io_context, data) = io_context_custom(Vec::new(), true)?;
+    let output_format_context =
+        AVFormatContextOutput::create(c".mp4", Some(AVIOContextContainer::Custom(io_context)))?;
+
+    Ok((output_format_context, data))
+}
+
+fn new_frame(width: i32, height: i32, format: i32) -> Result<AVFrame> {
+    let mut frame = AVFrame::new();
+    frame.set_width(width);
+    frame.set_height(height);
+    frame.set_format(format);
+    frame.alloc_buffer()?;
+    Ok(frame)
+}
+
+/// `text` must not contain quotes.
+fn drawtext_filter(text: &str, options: &Options) -> String {
+    let font = options
+        .overlay_font
+        .as_ref()
+        .map(|f| format!(":fontfile='{}'", f))
+        .unwrap_or_default();
+    format!(
+        "drawtext=text='{}'{}:fontsize=h/16:fontcolor=white:box=1:boxcolor=black@0.5\
+         :x=w-tw-h/40:y=h-th-h/40,format=yuv420p",
+        text.replace(':', "\\:"),
+        font
+    )
+}
+
+fn drain_filter(
+    sink_context: &mut AVFilterContext,
+    encode_context: &mut AVCodecContext,
+    output_format_context: &mut AVFormatContextOutput,
+) -> Result<()> {
+    loop {
+        match sink_context.buffersink_get_frame(None) {
+            Ok(frame) => {
+                encode_write_frame(Some(&frame), encode_context, output_format_context, 0)?
+            }
+            Err(RsmpegError::BufferSinkDrainError) | Err(RsmpegError::BufferSinkEofError) => {
+                break Ok(())
+            }
+            Err(e) => break Err(e.into()),
+        }
+    }
+}
+
+fn encode_mp4(mut src: AVFrameIter, options: &Options, overlay: Option<&str>) -> Result<Mp4> {
+    let (buffer, truncated) = {
+        let time_base = src.decode_context.time_base;
+        let framerate = src.decode_context.framerate;
+        let first_frame = src.next_frame()?.context("Failed to get first frame")?;
+        let &ffi::AVFrame {
+            width: src_width,
+            height: src_height,
+            format: src_format,
+            ..
+        } = (*first_frame).deref();
+        let dst_width = src_width + src_width % 2;
+        let dst_height = src_height + src_height % 2;
+        let dst_format = ffi::AVPixelFormat_AV_PIX_FMT_YUV420P;
+
+        let (mut output_format_context, buffer) = output_format_context()?;
+
+        let encoder =
+            AVCodec::find_encoder_by_name(c"libx264").context("Failed to find encoder codec")?;
+        let mut encode_context = AVCodecContext::new(&encoder);
+        encode_context.set_width(dst_width);
+        encode_context.set_height(dst_height);
+        encode_context.set_pix_fmt(dst_format);
+        encode_context.set_time_base(time_base);
+        encode_context.set_framerate(framerate);
+        unsafe {
+            if ffi::av_opt_set(
+                encode_context.priv_data,
+                c"preset".as_ptr(),
+                c"slow".as_ptr(),
+                0,
+            ) < 0
+            {
+                bail!("Failed to set preset");
+            }
+            let threads = CString::new(options.threads.to_string()).unwrap();
+            if ffi::av_opt_set(
+                encode_context.as_mut_ptr().cast(),
+                c"threads".as_ptr(),
+                threads.as_ptr(),
+                0,
+            ) < 0
+            {
+                bail!("Failed to set threads");
+            }
+        }
+        if output_format_context.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
+            encode_context
+                .set_flags(encode_context.flags | ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
+        }
+        encode_context.open(None)?;
+
+        let mut dst_frame = new_frame(dst_width, dst_height, dst_format)?;
+
+        let filter_graph = AVFilterGraph::new();
+        let mut overlay = match overlay {
+            Some(text) => {
+                let args = format!(
+                    "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect=1/1",
+                    dst_width, dst_height, dst_format, time_base.num, time_base.den
+                );
+                let args = CString::new(args).unwrap();
+                let filter_spec = CString::new(drawtext_filter(text, options))
+                    .context("Overlay text contains NUL")?;
+                let buffer_src =
+                    AVFilter::get_by_name(c"buffer").context("Failed to find buffer filter")?;
+                let buffer_sink = AVFilter::get_by_name(c"buffersink")
+                    .context("Failed to find buffersink filter")?;
+                let mut src_context =
+                    filter_graph.create_filter_context(&buffer_src, c"in", Some(&args))?;
+                let mut sink_context =
+                    filter_graph.create_filter_context(&buffer_sink, c"out", None)?;
+                let outputs = AVFilterInOut::new(c"in", &mut src_context, 0);
+                let inputs = AVFilterInOut::new(c"out", &mut sink_context, 0);
+                filter_graph.parse_ptr(&filter_spec, Some(inputs), Some(outputs))?;
+                filter_graph.config()?;
+                Some((src_context, sink_context))
+            }
+            None => None,
+        };
+
+        {
+            let mut out_stream = output_format_context.new_stream();
+            out_stream.set_codecpar(encode_context.extract_codecpar());
+        }
+
+        output_format_context.write_header(&mut None)?;
+
+        let mut sws_context =
+            if src_format != dst_frame.format || src_width != dst_width || src_height != dst_height
+            {
+                let sws_context = SwsContext::get_context(
+                    src_width,
+                    src_height,
+                    src_format,
+                    dst_width,
+                    dst_height,
+                    dst_format,
+                    ffi::SWS_FAST_BILINEAR | ffi::SWS_ACCURATE_RND,
+                )
+                .context("Failed to get sws_context")?;
+                Some(sws_context)
+            } else {
+                None
+            };
+
+        let mut encode_frame = |src_frame: &mut AVFrame| -> Result<()> {
+            let frame_after = if let Some(sws_context) = sws_context.as_mut() {
+                sws_context.scale_frame(src_frame, 0, src_height, &mut dst_frame)?;
+                dst_frame.set_pts(src_frame.pts);
+                &mut dst_frame
+            } else {
+                src_frame
+            };
+
+            match overlay.as_mut() {
+                None => encode_write_frame(
+                    Some(frame_after),
+                    &mut encode_context,
+                    &mut output_format_context,
+                    0,
+                ),
+                Some((src_context, sink_context)) => {
+                    // the filter keeps referencing the frame, so hand it
+                    // over instead of scaling into the same buffer again
+                    let frame = std::mem::replace(
+                        frame_after,
+                        new_frame(dst_width, dst_height, dst_format)?,
+                    );
+                    src_context.buffersrc_add_frame(Some(frame), None)?;
+                    drain_filter(
+                        sink_context,
+                        &mut encode_context,
+                        &mut output_format_context,
+                    )
+                }
+            }
+        };
+        let first_pts = first_frame.pts;
+        encode_frame(first_frame)?;
+        let mut frames = 1;
+        let mut truncated = false;
+        while let Some(src_frame) = src.next_frame()? {
+            let elapsed = src_frame.pts.saturating_sub(first_pts);
+            let seconds = elapsed as f64 * time_base.num as f64 / time_base.den as f64;
+            if options.max_frames.is_some_and(|max| frames >= max)
+                || options.max_duration.is_some_and(|max| seconds >= max)
+            {
+                truncated = true;
+                break;
+            }
+            encode_frame(src_frame)?;
+            frames += 1;
+        }
+
+        if let Some((src_context, sink_context)) = overlay.as_mut() {
+            src_context.buffersrc_add_frame(None, None)?;
+            drain_filter(
+                sink_context,
+                &mut encode_context,
+                &mut output_format_context,
+            )?;
+        }
+        encode_write_frame(None, &mut encode_context, &mut output_format_context, 0)?;
+        output_format_context.write_trailer()?;
+
+        (buffer, truncated)
+    };
+
+    let data = Arc::into_inner(buffer)
+        .context("Failed to get buffer")?
+        .into_inner()?
+        .into_inner();
+
+    Ok(Mp4 { data, truncated })
+}
+
+fn encode_write_frame(
+    frame_after: Option<&AVFrame>,
+    encode_context: &mut AVCodecContext,
+    output_format_context: &mut AVFormatContextOutput,
+    out_stream_index: usize,
+) -> Result<()> {
+    encode_context.send_frame(frame_after)?;
+
+    loop {
+        let mut packet = match encode_context.receive_packet() {
+            Ok(packet) => packet,
+            Err(RsmpegError::EncoderDrainError) | Err(RsmpegError::EncoderFlushedError) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        packet.set_stream_index(out_stream_index as i32);
+        packet.rescale_ts(
+            encode_context.time_base,
+            output_format_context
+                .streams()
+                .get(out_stream_index)
+                .context("Failed to get stream")?
+                .time_base,
+        );
+
+        match output_format_context.interleaved_write_frame(&mut packet) {
+            Ok(()) => Ok(()),
+            Err(RsmpegError::InterleavedWriteFrameError(-22)) => Ok(()),
+            Err(e) => Err(e),
+        }?;
+    }
+
+    Ok(())
+}
+
+/// Index of the video stream if `input` is already an MP4 telegram plays
+/// as is: a single H.264 yuv420p stream that fits in `max_frames` and
+/// `max_duration`. Anything else, including inputs with audio, is
+/// re-encoded.
+fn remuxable(input: &AVFormatContextInput, options: &Options) -> Option<usize> {
+    let format = unsafe { CStr::from_ptr((*input.iformat).name) };
+    if !format.to_bytes().split(|&c| c == b',').any(|f| f == b"mp4") {
+        return None;
+    }
+    let streams = input.streams();
+    if streams.len() != 1 {
+        return None;
+    }
+    let stream = streams.get(0)?;
+    let codecpar = stream.codecpar();
+    if codecpar.codec_type != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        || codecpar.codec_id != ffi::AVCodecID_AV_CODEC_ID_H264
+        || codecpar.format != ffi::AVPixelFormat_AV_PIX_FMT_YUV420P
+    {
+        return None;
+    }
+    // unknown frame counts and durations are zero or negative
+    if let Some(max) = options.max_frames {
+        if stream.nb_frames <= 0 || stream.nb_frames as usize > max {
+            return None;
+        }
+    }
+    if let Some(max) = options.max_duration {
+        let seconds = input.duration as f64 / ffi::AV_TIME_BASE as f64;
+        if input.duration <= 0 || seconds > max {
+            return None;
+        }
+    }
+    Some(0)
+}
+
+/// Copies the packets of `stream_index` into a fresh MP4 without decoding
+fn remux_mp4(mut input: AVFormatContextInput, stream_index: usize) -> Result<Vec<u8>> {
+    let buffer = {
+        let (mut output_format_context, buffer) = output_format_context()?;
+        let in_time_base = {
+            let in_stream = input
+                .streams()
+                .get(stream_index)
+                .context("Failed to get stream")?;
+            let mut out_stream = output_format_context.new_stream();
+            out_stream.set_codecpar(in_stream.codecpar().clone());
+            in_stream.time_base
+        };
+        output_format_context.write_header(&mut None)?;
+        let out_time_base = output_format_context
+            .streams()
+            .get(0)
+            .context("Failed to get stream")?
+            .time_base;
+
+        while let Some(mut packet) = input.read_packet()? {
+            if packet.stream_index != stream_index as i32 {
+                continue;
+            }
+            packet.set_stream_index(0);
+            packet.rescale_ts(in_time_base, out_time_base);
+            packet.set_pos(-1);
+            output_format_context.interleaved_write_frame(&mut packet)?;
+        }
+        output_format_context.write_trailer()?;
+
+        buffer
+    };
+
+    Ok(Arc::into_inner(buffer)
+        .context("Failed to get buffer")?
+        .into_inner()?
+        .into_inner())
+}
+
+/// Remuxes `data` if it can be sent as is, re-encodes it otherwise. The
+/// result still needs `faststart`.
+pub fn video_to_mp4(data: Vec<u8>, options: &Options, overlay: Option<&str>) -> Result<Mp4> {
+    let format_context = input_format_context(data)?;
+    let remux = match overlay {
+        None => remuxable(&format_context, options),
+        Some(_) => None,
+    };
+    if let Some(stream_index) = remux {
+        return Ok(Mp4 {
+            data: remux_mp4(format_context, stream_index)?,
+            truncated: false,
+        });
+    }
+    let frame_iter = decode_video(format_context)?;
+
+    encode_mp4(frame_iter, options, overlay)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gif() -> Vec<u8> {
+        let mut r = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut r);
+            let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].iter().map(|&color| {
+                image::Frame::new(image::RgbaImage::from_pixel(16, 16, image::Rgba(color)))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        r
+    }
+
+    #[test]
+    fn remux_h264() {
+        let options = Options::default();
+        let encoded = video_to_mp4(gif(), &options, None).unwrap().data;
+        let input = input_format_context(encoded.clone()).unwrap();
+        assert_eq!(remuxable(&input, &options), Some(0));
+
+        let remuxed = video_to_mp4(encoded, &options, None).unwrap();
+        assert!(!remuxed.truncated);
+        let input = input_format_context(remuxed.data).unwrap();
+        assert_eq!(remuxable(&input, &options), Some(0));
+
+        let short = Options {
+            max_frames: Some(1),
+            ..Options::default()
+        };
+        assert_eq!(remuxable(&input, &short), None);
+    }
+}
@@ -1,63 +1,874 @@
 use std::collections::HashMap;
+use std::env;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tbot::types::parameters::ChatId;
 use tokio::fs;
 
+use crate::{archiver, convert, history, http, preprocess, run_lock, spider, update_check};
+
+/// Schema version written by this build.
+const VERSION: u64 = 2;
+
+/// `MIGRATIONS[n]` upgrades a version `n` database to version `n + 1`.
+const MIGRATIONS: [fn(&mut Value) -> anyhow::Result<()>; VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Post stats older than this are dropped
+const STATS_DAYS: u64 = 90;
+
+/// v0 is the original layout without a `version` field; nothing else changed.
+fn v0_to_v1(_db: &mut Value) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// v2 stores asset and comment mappings as `MessageRef`s. Old entries don't
+/// know their chat and keep being linked in the one configured now.
+fn v1_to_v2(db: &mut Value) -> anyhow::Result<()> {
+    let obj = db.as_object_mut().context("db.json is not an object")?;
+    for field in ["imgs", "comments"] {
+        let map = match obj.get_mut(field).and_then(Value::as_object_mut) {
+            Some(map) => map,
+            None => continue,
+        };
+        for v in map.values_mut() {
+            let msg = v
+                .as_u64()
+                .with_context(|| format!("invalid entry in {}", field))?;
+            *v = serde_json::json!({ "msg": msg });
+        }
+    }
+    Ok(())
+}
+
+/// Upgrades `db` in place, returns `true` if anything was changed.
+fn migrate(db: &mut Value) -> anyhow::Result<bool> {
+    let obj = db.as_object_mut().context("db.json is not an object")?;
+    let version = match obj.get("version") {
+        None => 0,
+        Some(v) => v.as_u64().context("invalid version in db.json")?,
+    };
+    if version > VERSION {
+        bail!(
+            "db.json version {} is newer than supported version {}",
+            version,
+            VERSION
+        );
+    }
+    if version == VERSION {
+        return Ok(false);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(db)?;
+    }
+    db.as_object_mut()
+        .context("migration replaced the db.json object")?
+        .insert("version".into(), VERSION.into());
+    Ok(true)
+}
+
+/// Environment variables over the db.json fields they set, so containers
+/// are configured without editing files. `assets_channel` falls back to
+/// `channel` when neither has it. The values never end up in db.json.
+const ENV_FIELDS: [(&str, &str); 3] = [
+    ("TOKEN", "token"),
+    ("CHANNEL", "channel"),
+    ("ASSETS_CHANNEL", "assets_channel"),
+];
+
+/// A field `apply_env` replaced and what db.json had in it
+type Shadowed = (&'static str, Option<Value>);
+
+/// Sets the fields of `ENV_FIELDS` that `var` has a value for
+fn apply_env(
+    db: &mut Value,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<Shadowed>> {
+    let obj = db.as_object_mut().context("db.json is not an object")?;
+    let mut shadowed = Vec::new();
+    for (name, field) in ENV_FIELDS {
+        if let Some(value) = var(name) {
+            shadowed.push((field, obj.insert(field.into(), value.into())));
+        }
+    }
+    if !obj.contains_key("assets_channel") {
+        if let Some(channel) = obj.get("channel").cloned() {
+            obj.insert("assets_channel".into(), channel);
+            shadowed.push(("assets_channel", None));
+        }
+    }
+    Ok(shadowed)
+}
+
+/// Puts the db.json values of fields set from the environment back
+fn unshadow(db: &mut Value, shadowed: &[Shadowed]) {
+    let obj = match db.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+    for (field, value) in shadowed {
+        match value {
+            Some(value) => obj.insert((*field).into(), value.clone()),
+            None => obj.remove(*field),
+        };
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Database {
     #[serde(skip)]
     file: PathBuf,
+    /// Send everything here instead, see `set_channel_override`
+    #[serde(skip)]
+    channel_override: Option<String>,
+    /// Section being posted, see `set_section`
+    #[serde(skip)]
+    section: Option<String>,
+    /// See `apply_env`
+    #[serde(skip)]
+    shadowed: Vec<Shadowed>,
+    /// The file was missing and is started fresh
+    #[serde(skip)]
+    created: bool,
+    version: u64,
     pub token: String,
     pub channel: String,
     pub assets_channel: String,
-    imgs: HashMap<String, u64>,
-    comments: HashMap<u64, u64>,
+    /// Where mentioned comments are mirrored, `assets_channel` if unset
+    #[serde(default)]
+    pub mentions_channel: Option<String>,
+    /// Seconds a run may spend posting before it leaves the rest of the
+    /// backlog to the next run, keeps cron runs from overlapping
+    #[serde(default)]
+    pub max_run_duration: Option<u64>,
+    /// Runs fail early if the working, temp or journal directory has less
+    /// than this many MiB free
+    #[serde(default)]
+    pub min_free_mb: Option<u64>,
+    /// Only one instance runs at a time when several share this database
+    #[serde(default)]
+    pub run_lock: Option<run_lock::Config>,
+    /// Forward posts that reach enough OO to a second channel, needs
+    /// `refresh_captions` to see the votes change
+    #[serde(default)]
+    pub best_of: Option<BestOf>,
+    /// Front page lists to post, only `pic` to `channel` if empty
+    #[serde(default)]
+    pub sections: Vec<Section>,
+    /// Tried in order until one answers, jandan.net if empty
+    #[serde(default)]
+    pub endpoints: Vec<spider::Endpoint>,
+    /// Older pages of a section to go through when none of its front page
+    /// posts were seen before, 0 only reads the front page
+    #[serde(default)]
+    pub max_pages: usize,
+    /// Posts whose tucao are fetched at the same time, 4 if unset
+    #[serde(default)]
+    pub comment_fetch_concurrency: Option<usize>,
+    /// Runs in these windows exit without fetching jandan
+    #[serde(default)]
+    pub blackouts: Vec<Blackout>,
+    /// Spread posts out when a run finds a large backlog
+    #[serde(default)]
+    pub catch_up: Option<CatchUp>,
+    /// Posts held back by catch-up mode, oldest first
+    #[serde(default)]
+    pub catch_up_queue: Vec<history::Key>,
+    /// When catch-up mode last sent a post of the queue
+    #[serde(default)]
+    pub catch_up_released_at: Option<u64>,
+    /// New posts below these are left for a later run, their votes may
+    /// still grow while they are on the front page
+    #[serde(default)]
+    pub vote_filter: Option<VoteFilter>,
+    #[serde(default)]
+    pub blocklist: Blocklist,
+    #[serde(default)]
+    pub quarantine: Option<Quarantine>,
+    /// Reply to photo albums with the original files as documents
+    #[serde(default)]
+    pub attach_originals: bool,
+    /// Send converted GIFs with sendAnimation so every client autoplays and
+    /// loops them, posts with GIFs are then no longer sent as albums
+    #[serde(default)]
+    pub gif_as_animation: bool,
+    /// Images whose height/width is above this ratio are sliced into an
+    /// album, with the original attached as a document
+    #[serde(default)]
+    pub long_strip_ratio: Option<f32>,
+    /// Images whose long side is more than this many times the short side,
+    /// e.g. panoramas, are sent as documents. Tall ones are sliced first if
+    /// `long_strip_ratio` is set.
+    #[serde(default)]
+    pub max_photo_ratio: Option<f32>,
+    /// Markdown lines put before/after the caption, see `render_template`
+    #[serde(default)]
+    pub caption_header: Option<String>,
+    #[serde(default)]
+    pub caption_footer: Option<String>,
+    /// Line after the post link telling when it went up, `{date}` and
+    /// `{time}` are in jandan's time (UTC+8), e.g. "{date} {time}"
+    #[serde(default)]
+    pub published_format: Option<String>,
+    /// Larger posts are cut down to their largest images, with a link to
+    /// the full post
+    #[serde(default)]
+    pub max_images: Option<usize>,
+    /// Posts with fewer images are sent as separate messages instead of an
+    /// album
+    #[serde(default)]
+    pub min_album_size: usize,
+    /// Merge consecutive posts numbered like "1/3" and posted within an
+    /// hour of each other into one album
+    #[serde(default)]
+    pub merge_multipart: bool,
+    /// Put a grid of all images in front of albums made of small thumbnails
+    #[serde(default)]
+    pub collage_thumbnails: bool,
+    /// Stand in for images that fail to download with a plain image in the
+    /// colors of their thumbnail, so albums stay albums
+    #[serde(default)]
+    pub failed_image_placeholders: bool,
+    /// Screenshots the post page when none of its images can be fetched,
+    /// e.g. a headless browser. `{url}` and `{output}` are replaced by the
+    /// page and the PNG path.
+    #[serde(default)]
+    pub screenshot_command: Option<Vec<String>>,
+    /// Follow mention links with the start of the mentioned comment
+    #[serde(default)]
+    pub quote_mentions: bool,
+    /// Start hot comments that reply to another comment with a line of it
+    #[serde(default)]
+    pub reply_context: bool,
+    /// Post texts and comments longer than this many characters are
+    /// collapsed into expandable quotes
+    #[serde(default)]
+    pub expandable_quote_over: Option<usize>,
+    /// Announce new releases of the bot to a chat, off if unset
+    #[serde(default)]
+    pub update_check: Option<update_check::Config>,
+    /// When `update_check` last asked GitHub and what it announced
+    #[serde(default)]
+    pub release_check: update_check::State,
+    #[serde(default)]
+    pub circuit_breaker: Option<http::BreakerConfig>,
+    /// Hosts that failed lately, see `http::circuits`
+    #[serde(default)]
+    pub circuits: HashMap<String, http::Circuit>,
+    /// Applied to media URLs in order, the first match wins
+    #[serde(default)]
+    pub image_rewrites: Vec<http::Rewrite>,
+    /// Drop the hot comments that don't fit in the caption, with a link to
+    /// the rest, instead of sending them as extra messages
+    #[serde(default)]
+    pub single_caption: bool,
+    /// Like `single_caption`, allowing this many messages after the caption
+    #[serde(default)]
+    pub max_extra_captions: Option<usize>,
+    /// Order of the hot comments in captions
+    #[serde(default)]
+    pub comment_order: spider::CommentOrder,
+    /// Posts deleted or censored before they went out are announced with
+    /// their author and votes instead of being skipped quietly
+    #[serde(default)]
+    pub note_deleted: bool,
+    /// Post only the pictures, tucao are neither fetched nor mirrored
+    #[serde(default)]
+    pub skip_comments: bool,
+    /// Enlarge tiny images before sending
+    #[serde(default)]
+    pub upscale: Option<preprocess::Upscale>,
+    /// Send the post before uploading comment assets, the caption is edited
+    /// once they are up
+    #[serde(default)]
+    pub post_before_assets: bool,
+    #[serde(default)]
+    pub convert: convert::Options,
+    #[serde(default)]
+    pub last_run: RunInfo,
+    /// Takes precedence over the wayback machine token on the command line
+    #[serde(default)]
+    pub archiver: Option<archiver::Config>,
+    /// Posts waiting to be archived
+    #[serde(default)]
+    pub archive_queue: Vec<ArchiveJob>,
+    /// Keep the votes and hot comments in captions up to date
+    #[serde(default)]
+    pub refresh_captions: bool,
+    /// Posts whose captions are refreshed
+    #[serde(default)]
+    pub tracked: Vec<Tracked>,
+    /// Sent posts of the last `STATS_DAYS` days, for the stats subcommand
+    #[serde(default)]
+    pub stats: Vec<PostStats>,
+    imgs: HashMap<String, MessageRef>,
+    comments: HashMap<u64, MessageRef>,
+    /// SHA-256 of uploaded assets to their message in `assets_channel`
+    #[serde(default)]
+    img_hashes: HashMap<String, MessageRef>,
+    /// Hot comment ID to the channel message of the post it was shown in
+    #[serde(default)]
+    posted_comments: HashMap<u64, MessageRef>,
+}
+
+/// A sent message that links point to, with the chat it went to so the
+/// links survive a change of the configured channels
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MessageRef {
+    /// Missing for messages recorded before v2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chat: Option<String>,
+    msg: u64,
+}
+
+impl MessageRef {
+    fn link(&self, configured: &str) -> String {
+        message_link(self.chat.as_deref().unwrap_or(configured), self.msg)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CatchUp {
+    /// Catch-up mode kicks in when more than this many posts are new
+    pub threshold: usize,
+    /// Seconds between two posts sent from the queue, runs in between
+    /// send nothing
+    pub interval: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VoteFilter {
+    #[serde(default)]
+    pub min_oo: u32,
+    /// OO per XX, a post without XX passes
+    #[serde(default)]
+    pub min_ratio: f64,
+}
+
+impl VoteFilter {
+    pub fn accepts(&self, oo: u32, xx: u32) -> bool {
+        oo >= self.min_oo && (xx == 0 || oo as f64 / xx as f64 >= self.min_ratio)
+    }
+}
+
+/// Posts by one of `authors` or with one of `keywords` are never sent,
+/// comments matching them are left out
+#[derive(Default, Deserialize, Serialize)]
+pub struct Blocklist {
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Matched ignoring case
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn blocks_author(&self, author: &str) -> bool {
+        self.authors.iter().any(|a| a.trim() == author.trim())
+    }
+    pub fn blocks_text(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|k| !k.is_empty() && text.contains(&k.to_lowercase()))
+    }
+}
+
+/// Skips posts that keep killing runs. Every failed attempt at a post
+/// leaves a journal, so their number survives crashes; `cleanup` resets
+/// it.
+#[derive(Deserialize, Serialize)]
+pub struct Quarantine {
+    /// Failed attempts after which the post is skipped
+    pub after: usize,
+    /// Told once about each skipped post
+    #[serde(default)]
+    pub chat: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Section {
+    /// As in the `#list-<name>` of jandan's front page, e.g. "ooxx"
+    pub name: String,
+    /// `channel` if unset
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// `skip_comments` if unset
+    #[serde(default)]
+    pub skip_comments: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BestOf {
+    pub channel: String,
+    pub min_oo: u32,
+}
+
+/// Daily window in jandan's time (UTC+8), `"HH:MM"`, may wrap midnight
+#[derive(Deserialize, Serialize)]
+pub struct Blackout {
+    pub start: String,
+    pub end: String,
+}
+
+impl Blackout {
+    pub fn contains(&self, unix_time: u64) -> anyhow::Result<bool> {
+        const JANDAN_UTC_OFFSET: u64 = 8 * 60;
+        let minute = (unix_time / 60 + JANDAN_UTC_OFFSET) % (24 * 60);
+        let (start, end) = (parse_time(&self.start)?, parse_time(&self.end)?);
+        Ok(if start <= end {
+            (start..end).contains(&minute)
+        } else {
+            minute >= start || minute < end
+        })
+    }
+}
+
+/// Minutes since midnight
+fn parse_time(s: &str) -> anyhow::Result<u64> {
+    let parsed = s
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u64>().ok()?, m.parse::<u64>().ok()?)));
+    match parsed {
+        Some((h, m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => bail!("bad blackout time {:?}, expected HH:MM", s),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Tracked {
+    #[serde(default = "default_section")]
+    pub section: String,
+    pub id: String,
+    pub posted_at: u64,
+    pub refreshed_at: u64,
+    /// Messages carrying the captions, the first one may be a media caption
+    pub messages: Vec<u32>,
+    pub media_caption: bool,
+    pub captions: Vec<String>,
+    pub notes: Vec<String>,
+    /// Every message of the post, for forwarding
+    #[serde(default)]
+    pub post_messages: Vec<u32>,
+    /// Already forwarded to the best of channel
+    #[serde(default)]
+    pub forwarded: bool,
+}
+
+fn default_section() -> String {
+    spider::SECTION.to_owned()
+}
+
+impl Tracked {
+    /// Whether this is `pic` and it's time to refresh it
+    pub fn is_due(&self, pic: &spider::Pic, now: u64) -> bool {
+        self.section == pic.section
+            && self.id == pic.id
+            && self.next_refresh().is_some_and(|at| at <= now)
+    }
+    /// Posts are refreshed hourly for their first 6 hours, every 4 hours
+    /// until they are 2 days old and daily for the rest of the week, so the
+    /// load on jandan doesn't grow with the number of tracked posts
+    pub fn next_refresh(&self) -> Option<u64> {
+        const HOUR: u64 = 60 * 60;
+        let interval = match self.refreshed_at.saturating_sub(self.posted_at) {
+            age if age < 6 * HOUR => HOUR,
+            age if age < 48 * HOUR => 4 * HOUR,
+            age if age < 7 * 24 * HOUR => 24 * HOUR,
+            _ => return None,
+        };
+        Some(self.refreshed_at + interval)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PostStats {
+    pub id: String,
+    pub author: String,
+    pub posted_at: u64,
+    /// Votes when the post was sent
+    pub oo: u32,
+    pub xx: u32,
+    pub gifs: usize,
+    pub failed_gifs: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ArchiveJob {
+    pub id: String,
+    pub attempts: u32,
+}
+
+impl ArchiveJob {
+    pub fn new(id: String) -> Self {
+        ArchiveJob { id, attempts: 0 }
+    }
+}
+
+/// Bookkeeping of the latest run, all times are unix timestamps
+#[derive(Default, Deserialize, Serialize)]
+pub struct RunInfo {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub succeeded: bool,
+    /// Posts sent by the latest run
+    pub posted: usize,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+    /// Set when the bot lost access to the channel, runs only check whether
+    /// access is back until then
+    #[serde(default)]
+    pub paused_since: Option<u64>,
+    /// The chat the bot lost access to, checked before runs go on
+    #[serde(default)]
+    pub paused_chat: Option<String>,
+    /// Jandan endpoint the latest run fetched from
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+impl RunInfo {
+    pub fn start(&mut self) {
+        self.started_at = now();
+        self.finished_at = None;
+        self.succeeded = false;
+        self.posted = 0;
+    }
+    pub fn finish(&mut self, error: Option<&anyhow::Error>) {
+        let now = now();
+        self.finished_at = Some(now);
+        self.succeeded = error.is_none();
+        if let Some(e) = error {
+            self.last_error = Some(format!("{:#}", e));
+            self.last_error_at = Some(now);
+        } else {
+            self.last_success_at = Some(now);
+        }
+    }
+}
+
+/// Link to message `msg` of `chat`, a `@username` or a numeric ID. Chats
+/// without a username, e.g. after a migration to a supergroup, are only
+/// linked for their members.
+pub fn message_link(chat: &str, msg: u64) -> String {
+    match chat.strip_prefix("-100") {
+        Some(internal) if internal.parse::<u64>().is_ok() => {
+            format!("https://t.me/c/{}/{}", internal, msg)
+        }
+        _ => format!("https://t.me/{}/{}", chat.trim_start_matches('@'), msg),
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before 1970")
+        .as_secs()
 }
 
 impl Database {
+    /// Missing `file` is started fresh if the environment has a `TOKEN`
     pub async fn open<P: AsRef<Path>>(file: P) -> Result<Self, anyhow::Error> {
-        let s = fs::read_to_string(&file).await?;
-        let mut r: Self = serde_json::from_str(&s)?;
+        Self::open_with(file, &|var| env::var(var).ok()).await
+    }
+    async fn open_with<P: AsRef<Path>>(
+        file: P,
+        var: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let (mut v, created) = match fs::read_to_string(&file).await {
+            Ok(s) => (serde_json::from_str(&s)?, false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && var("TOKEN").is_some() => {
+                let v = serde_json::json!({ "version": VERSION, "imgs": {}, "comments": {} });
+                (v, true)
+            }
+            Err(e) => return Err(e).with_context(|| format!("read {}", file.as_ref().display())),
+        };
+        let migrated = migrate(&mut v)?;
+        let shadowed = apply_env(&mut v, var)?;
+        let mut r: Self = serde_json::from_value(v)?;
         r.file = file.as_ref().into();
+        r.shadowed = shadowed;
+        r.created = created;
+        if migrated || created {
+            r.save().await?;
+        }
         Ok(r)
     }
+    /// Redirects every send to `chat`. Asset mappings start out empty so
+    /// they are uploaded again, and nothing is saved back to the file.
+    pub fn set_channel_override(&mut self, chat: String) {
+        self.imgs.clear();
+        self.comments.clear();
+        self.posted_comments.clear();
+        self.img_hashes.clear();
+        self.channel_override = Some(chat);
+    }
+    pub fn section_names(&self) -> Vec<String> {
+        if self.sections.is_empty() {
+            return vec![spider::SECTION.to_owned()];
+        }
+        self.sections.iter().map(|s| s.name.clone()).collect()
+    }
+    /// Sends to the channel of `section` from now on
+    pub fn set_section(&mut self, section: &str) {
+        self.section = Some(section.to_owned());
+    }
+    pub fn is_channel_overridden(&self) -> bool {
+        self.channel_override.is_some()
+    }
     pub async fn save(&self) -> Result<(), anyhow::Error> {
-        let s = serde_json::to_string_pretty(self)?;
-        fs::write(&self.file, s).await?;
+        if self.is_channel_overridden() {
+            return Ok(());
+        }
+        let mut v = serde_json::to_value(self)?;
+        unshadow(&mut v, &self.shadowed);
+        let s = serde_json::to_string_pretty(&v)?;
+        // a crash mid-write must not leave a truncated database behind
+        let tmp = self.file.with_extension("json.tmp");
+        fs::write(&tmp, s).await?;
+        fs::rename(&tmp, &self.file).await?;
         Ok(())
     }
+    /// Whether `open` started a new database
+    pub fn is_new(&self) -> bool {
+        self.created
+    }
     pub fn channel(&self) -> ChatId<'_> {
-        self.channel.as_str().into()
+        self.channel_name().into()
+    }
+    pub fn channel_name(&self) -> &str {
+        let section = self
+            .sections
+            .iter()
+            .find(|s| self.section.as_deref() == Some(&*s.name));
+        self.channel_override
+            .as_deref()
+            .or(section.and_then(|s| s.channel.as_deref()))
+            .unwrap_or(&self.channel)
+    }
+    /// Where posts of `section` go outside of test runs
+    pub fn live_channel_name(&self, section: &str) -> &str {
+        self.sections
+            .iter()
+            .find(|s| s.name == section)
+            .and_then(|s| s.channel.as_deref())
+            .unwrap_or(&self.channel)
     }
     pub fn assets_channel(&self) -> ChatId<'_> {
-        self.assets_channel.as_str().into()
+        self.assets_channel_name().into()
+    }
+    fn assets_channel_name(&self) -> &str {
+        self.channel_override
+            .as_deref()
+            .unwrap_or(&self.assets_channel)
     }
     pub fn get_img(&self, url: &str) -> Option<String> {
-        self.imgs.get(url).map(|id| {
-            format!(
-                "https://t.me/{}/{}",
-                self.assets_channel.trim_start_matches('@'),
-                id
-            )
-        })
+        self.imgs
+            .get(url)
+            .map(|m| m.link(self.assets_channel_name()))
+    }
+    /// Whether posts of `section` go out without their tucao
+    pub fn skips_comments(&self, section: &str) -> bool {
+        self.sections
+            .iter()
+            .find(|s| s.name == section)
+            .and_then(|s| s.skip_comments)
+            .unwrap_or(self.skip_comments)
+    }
+    pub fn best_of_channel(&self) -> Option<&str> {
+        let best_of = self.best_of.as_ref()?;
+        Some(self.channel_override.as_deref().unwrap_or(&best_of.channel))
+    }
+    pub fn mentions_channel(&self) -> ChatId<'_> {
+        self.mentions_channel_name().into()
+    }
+    fn mentions_channel_name(&self) -> &str {
+        self.channel_override
+            .as_deref()
+            .or(self.mentions_channel.as_deref())
+            .unwrap_or(&self.assets_channel)
     }
     pub fn get_comment(&self, comment_id: u64) -> Option<String> {
-        self.comments.get(&comment_id).map(|msg_id| {
-            format!(
-                "https://t.me/{}/{}",
-                self.assets_channel.trim_start_matches('@'),
-                msg_id
-            )
-        })
+        self.comments
+            .get(&comment_id)
+            .map(|m| m.link(self.mentions_channel_name()))
+    }
+    pub fn get_posted_comment(&self, comment_id: u64) -> Option<String> {
+        self.posted_comments
+            .get(&comment_id)
+            .map(|m| m.link(self.channel_name()))
+    }
+    pub fn record_stats(&mut self, post: PostStats) {
+        let since = now().saturating_sub(STATS_DAYS * 24 * 60 * 60);
+        self.stats.retain(|p| p.posted_at >= since);
+        self.stats.push(post);
+    }
+    /// Asset mappings are saved together with the post they belong to
+    pub fn put_img(&mut self, url: String, asset: MessageRef) {
+        self.imgs.insert(url, asset);
     }
-    pub async fn put_img(&mut self, url: String, msg_id: u64) {
-        self.imgs.insert(url, msg_id);
-        let _ = self.save().await;
+    pub fn put_comment(&mut self, comment_id: u64, msg_id: u64) {
+        let chat = Some(self.mentions_channel_name().to_owned());
+        self.comments
+            .insert(comment_id, MessageRef { chat, msg: msg_id });
+    }
+    pub fn get_img_by_hash(&self, hash: &str) -> Option<MessageRef> {
+        self.img_hashes.get(hash).cloned()
+    }
+    /// Records an asset uploaded to `assets_channel` and returns it
+    pub fn put_img_hash(&mut self, hash: String, msg_id: u64) -> MessageRef {
+        let chat = Some(self.assets_channel_name().to_owned());
+        let asset = MessageRef { chat, msg: msg_id };
+        self.img_hashes.insert(hash, asset.clone());
+        asset
+    }
+    pub fn put_posted_comment(&mut self, comment_id: u64, msg_id: u64) {
+        let chat = Some(self.channel_name().to_owned());
+        self.posted_comments
+            .insert(comment_id, MessageRef { chat, msg: msg_id });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn migrate_v0() {
+        let mut db = serde_json::json!({ "token": "t" });
+        assert!(migrate(&mut db).unwrap());
+        assert_eq!(db["version"], VERSION);
+        assert!(!migrate(&mut db).unwrap());
+    }
+
+    #[test]
+    fn migrate_v1() {
+        let mut db = serde_json::json!({
+            "version": 1,
+            "imgs": { "https://example.com/a.jpg": 7 },
+            "comments": { "42": 8 },
+        });
+        assert!(migrate(&mut db).unwrap());
+        assert_eq!(db["imgs"]["https://example.com/a.jpg"]["msg"], 7);
+        assert_eq!(db["comments"]["42"]["msg"], 8);
+        assert!(db["comments"]["42"].get("chat").is_none());
     }
-    pub async fn put_comment(&mut self, comment_id: u64, msg_id: u64) {
-        self.comments.insert(comment_id, msg_id);
-        let _ = self.save().await;
+
+    #[test]
+    fn message_links() {
+        assert_eq!(message_link("@jandan_pic", 7), "https://t.me/jandan_pic/7");
+        assert_eq!(
+            message_link("-1001234567890", 7),
+            "https://t.me/c/1234567890/7"
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let env = |var: &str| match var {
+            "TOKEN" => Some("env token".to_owned()),
+            "CHANNEL" => Some("@env".to_owned()),
+            _ => None,
+        };
+        let mut db = serde_json::json!({ "token": "file token" });
+        let shadowed = apply_env(&mut db, &env).unwrap();
+        assert_eq!(db["token"], "env token");
+        assert_eq!(db["channel"], "@env");
+        assert_eq!(db["assets_channel"], "@env");
+        unshadow(&mut db, &shadowed);
+        assert_eq!(db, serde_json::json!({ "token": "file token" }));
+    }
+
+    #[tokio::test]
+    async fn missing_db() {
+        let file = std::env::temp_dir().join(format!("db.{}.json", std::process::id()));
+        let env = |var: &str| match var {
+            "TOKEN" => Some("t".to_owned()),
+            "CHANNEL" => Some("@channel".to_owned()),
+            _ => None,
+        };
+        assert!(Database::open_with(&file, &|_| None).await.is_err());
+
+        let db = Database::open_with(&file, &env).await.unwrap();
+        assert!(db.is_new());
+        assert_eq!(db.token, "t");
+        let saved: Value = serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert!(saved.get("token").is_none());
+        assert!(saved.get("channel").is_none());
+
+        let db = Database::open_with(&file, &env).await.unwrap();
+        assert!(!db.is_new());
+        assert_eq!(db.assets_channel, "@channel");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn refuse_future_version() {
+        let mut db = serde_json::json!({ "version": VERSION + 1 });
+        assert!(migrate(&mut db).is_err());
+    }
+
+    #[test]
+    fn refresh_schedule() {
+        let tracked = |age: u64| Tracked {
+            section: default_section(),
+            id: String::new(),
+            posted_at: 1000,
+            refreshed_at: 1000 + age,
+            messages: Vec::new(),
+            media_caption: false,
+            captions: Vec::new(),
+            notes: Vec::new(),
+            post_messages: Vec::new(),
+            forwarded: false,
+        };
+        assert_eq!(tracked(0).next_refresh(), Some(1000 + 3600));
+        assert_eq!(tracked(7 * 3600).next_refresh(), Some(1000 + 11 * 3600));
+        assert_eq!(tracked(50 * 3600).next_refresh(), Some(1000 + 74 * 3600));
+        assert_eq!(tracked(8 * 24 * 3600).next_refresh(), None);
+    }
+
+    #[test]
+    fn vote_filter() {
+        let filter = VoteFilter {
+            min_oo: 10,
+            min_ratio: 2.0,
+        };
+        assert!(filter.accepts(10, 0));
+        assert!(filter.accepts(20, 10));
+        assert!(!filter.accepts(9, 0));
+        assert!(!filter.accepts(19, 10));
+    }
+
+    #[test]
+    fn blocklist() {
+        let blocklist = Blocklist {
+            authors: vec!["spam".into()],
+            keywords: vec!["Buy Now".into(), String::new()],
+        };
+        assert!(blocklist.blocks_author(" spam"));
+        assert!(!blocklist.blocks_author("spammer"));
+        assert!(blocklist.blocks_text("please buy now!"));
+        assert!(!blocklist.blocks_text("buy later"));
+    }
+
+    #[test]
+    fn blackout() {
+        let window = |start: &str, end: &str| Blackout {
+            start: start.into(),
+            end: end.into(),
+        };
+        // 1970-01-01 03:00 in UTC+8
+        let t = 19 * 60 * 60;
+        assert!(window("02:00", "04:00").contains(t).unwrap());
+        assert!(!window("04:00", "05:00").contains(t).unwrap());
+        assert!(window("23:00", "03:30").contains(t).unwrap());
+        assert!(!window("23:00", "02:30").contains(t).unwrap());
+        assert!(window("25:00", "02:30").contains(t).is_err());
     }
 }
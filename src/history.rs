@@ -0,0 +1,163 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Section of the entries written before history had sections
+const LEGACY_SECTION: &str = "pic";
+/// First line of append-only files. Files without it are newest first and
+/// get rewritten on the next save.
+const HEADER: &str = "# oldest first";
+
+/// A posted jandan post, IDs are only unique within a section
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Key {
+    pub section: String,
+    pub id: String,
+}
+
+impl Key {
+    pub fn new(section: &str, id: &str) -> Self {
+        Key {
+            section: section.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    /// Parses `section/id`, a bare `id` is an entry of the old flat format
+    fn parse(line: &str) -> Self {
+        match line.split_once('/') {
+            Some((section, id)) => Key::new(section, id),
+            None => Key::new(LEGACY_SECTION, line),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.section, self.id)
+    }
+}
+
+/// Recently posted keys, kept in an append-only file that is compacted
+/// once it holds twice the keys it needs to. Files in the old flat or
+/// newest first formats are upgraded on the next save.
+pub struct History {
+    /// Oldest first, like the file
+    keys: Vec<Key>,
+    /// Keys in the file, including ones dropped from `keys`
+    lines: usize,
+    legacy: bool,
+}
+
+impl History {
+    /// A missing `file` is an empty history if `new`, e.g. next to the
+    /// database of a new container. Otherwise it was lost, and starting over
+    /// would post the whole front page again.
+    pub fn load<P: AsRef<Path>>(file: P, new: bool) -> io::Result<Self> {
+        let s = match fs::read_to_string(&file) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && new => {
+                warn!(
+                    "{} not found, starting a new history",
+                    file.as_ref().display()
+                );
+                String::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut lines = s.lines().map(str::trim).peekable();
+        let legacy = lines.peek() != Some(&HEADER);
+        let mut keys: Vec<Key> = lines
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Key::parse)
+            .collect();
+        if legacy {
+            keys.reverse();
+        }
+        Ok(History {
+            lines: keys.len(),
+            keys,
+            legacy,
+        })
+    }
+
+    pub fn contains(&self, section: &str, id: &str) -> bool {
+        self.keys.iter().any(|k| k.section == section && k.id == id)
+    }
+
+    /// Appends the keys of `fresh` that aren't saved yet, so it can be
+    /// called again as `fresh` grows. Compaction keeps the newest `size`.
+    pub fn save<P: AsRef<Path>>(&mut self, file: P, fresh: &[Key], size: usize) -> io::Result<()> {
+        let mut new = Vec::new();
+        for key in fresh {
+            if !self.contains(&key.section, &key.id) {
+                self.keys.push(key.clone());
+                new.push(key);
+            }
+        }
+        let file = file.as_ref();
+        if self.legacy || self.lines + new.len() > 2 * size {
+            let start = self.keys.len().saturating_sub(size);
+            self.keys.drain(..start);
+            let mut s = format!("{}\n", HEADER);
+            for key in &self.keys {
+                s.push_str(&format!("{}\n", key));
+            }
+            // a crash mid-write must not lose the history
+            let tmp = file.with_extension("tmp");
+            fs::write(&tmp, s)?;
+            fs::rename(&tmp, file)?;
+            self.lines = self.keys.len();
+            self.legacy = false;
+        } else if !new.is_empty() {
+            let mut f = OpenOptions::new().append(true).open(file)?;
+            let s: String = new.iter().map(|key| format!("{}\n", key)).collect();
+            f.write_all(s.as_bytes())?;
+            self.lines += new.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn legacy_entry() {
+        assert_eq!(Key::parse("123"), Key::new("pic", "123"));
+        assert_eq!(Key::parse("ooxx/123"), Key::new("ooxx", "123"));
+        assert_eq!(Key::new("pic", "123").to_string(), "pic/123");
+    }
+
+    #[test]
+    fn append_and_compact() {
+        let file = std::env::temp_dir().join(format!("history.{}.text", std::process::id()));
+        fs::write(&file, "2\n1").unwrap();
+        let key = |id| Key::new("pic", id);
+        let read = || fs::read_to_string(&file).unwrap();
+
+        let mut history = History::load(&file, false).unwrap();
+        history.save(&file, &[key("3")], 2).unwrap();
+        assert_eq!(read(), "# oldest first\npic/2\npic/3\n");
+
+        history.save(&file, &[key("3"), key("4")], 2).unwrap();
+        history.save(&file, &[key("3"), key("4")], 2).unwrap();
+        assert_eq!(read(), "# oldest first\npic/2\npic/3\npic/4\n");
+
+        let mut history = History::load(&file, false).unwrap();
+        assert!(history.contains("pic", "2"));
+        history.save(&file, &[key("5"), key("6")], 2).unwrap();
+        assert_eq!(read(), "# oldest first\npic/5\npic/6\n");
+        assert!(!history.contains("pic", "4"));
+        fs::remove_file(&file).unwrap();
+
+        // only a new database may come without a history
+        assert!(History::load(&file, false).is_err());
+        assert!(History::load(&file, true).unwrap().keys.is_empty());
+    }
+}
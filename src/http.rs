@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use reqwest::{header, redirect, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::database::now;
+
+const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://t.me/jandan_pic)"
+);
+const JANDAN_REFERER: &str = "https://jandan.net/";
+/// Image hosts that only serve hotlinks from jandan
+const JANDAN_REFERER_HOSTS: &[&str] = &["jandan.net", "sinaimg.cn", "moyu.im", "toto.im"];
+/// Hosts media is downloaded from, comment HTML could point anywhere
+const ALLOWED_HOSTS: &[&str] = &[
+    "jandan.net",
+    "sinaimg.cn",
+    "moyu.im",
+    "toto.im",
+    "weibocdn.com",
+];
+const MAX_REDIRECTS: usize = 5;
+/// `DOWNLOAD_CLIENT` gives up after 5s, videos get longer
+const VIDEO_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    static ref BREAKER: Mutex<Breaker> = Mutex::new(Breaker::default());
+    static ref REWRITES: Mutex<Vec<Rewrite>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    pub static CLIENT: reqwest::Client = {
+        let headers = header::HeaderMap::new();
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .user_agent(header::HeaderValue::from_static(USER_AGENT))
+            .default_headers(headers)
+            .build()
+            .unwrap()
+    };
+    /// Redirects are followed by hand so the referer can be fixed per hop
+    static DOWNLOAD_CLIENT: reqwest::Client = {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .user_agent(header::HeaderValue::from_static(USER_AGENT))
+            .redirect(redirect::Policy::none())
+            .build()
+            .unwrap()
+    };
+}
+
+/// The server answered with something that isn't media, typically an HTML
+/// error page with status 200. Retrying the same URL won't help.
+#[derive(Debug)]
+pub struct WrongContentType {
+    pub url: Url,
+    pub content_type: Option<String>,
+}
+
+impl fmt::Display for WrongContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not media (content-type: {})",
+            self.url,
+            self.content_type.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+impl std::error::Error for WrongContentType {}
+
+/// The URL, or a redirect, points outside of `ALLOWED_HOSTS`
+#[derive(Debug)]
+pub struct ForbiddenHost {
+    pub url: Url,
+}
+
+impl fmt::Display for ForbiddenHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to download {}, host not allowed", self.url)
+    }
+}
+
+impl std::error::Error for ForbiddenHost {}
+
+/// Stops requests to a host that keeps failing
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open the circuit
+    pub failures: u32,
+    /// Seconds until the host is tried again
+    pub cool_down: u64,
+}
+
+/// Failures of a host, kept in the database so they count across runs
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Circuit {
+    pub failures: u32,
+    pub open_until: Option<u64>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    config: Option<BreakerConfig>,
+    circuits: HashMap<String, Circuit>,
+}
+
+/// Sets up the circuit breaker with the circuits of earlier runs
+pub fn init_breaker(config: Option<BreakerConfig>, circuits: HashMap<String, Circuit>) {
+    *BREAKER.lock().unwrap() = Breaker { config, circuits };
+}
+
+/// The circuits to keep for the next run
+pub fn circuits() -> HashMap<String, Circuit> {
+    BREAKER.lock().unwrap().circuits.clone()
+}
+
+/// `host` failed too often, requests to it are refused until `until`
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub host: String,
+    pub until: u64,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} keeps failing, not trying again for {}s",
+            self.host,
+            self.until.saturating_sub(now())
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Media URLs starting with `from` are downloaded from `to` instead, for
+/// when jandan moves to another CDN. The host of `to` is allowed to
+/// download from and gets `referer`, the jandan one if unset.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rewrite {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub referer: Option<String>,
+}
+
+pub fn init_rewrites(rules: Vec<Rewrite>) {
+    *REWRITES.lock().unwrap() = rules;
+}
+
+/// The first of `rules` that matches `url`, with the rewritten URL
+fn rewrite<'a>(rules: &'a [Rewrite], url: &str) -> Option<(String, &'a Rewrite)> {
+    rules.iter().find_map(|rule| {
+        let rest = url.strip_prefix(&rule.from)?;
+        Some((format!("{}{}", rule.to, rest), rule))
+    })
+}
+
+/// Subdomains of the known hosts share a circuit, e.g. all of sinaimg.cn
+fn circuit_key(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    ALLOWED_HOSTS
+        .iter()
+        .find(|h| host == **h || host.ends_with(&format!(".{}", h)))
+        .map_or(host, |h| *h)
+        .to_owned()
+}
+
+fn check_circuit(url: &Url) -> Result<(), CircuitOpen> {
+    let breaker = BREAKER.lock().unwrap();
+    let key = circuit_key(url);
+    match breaker.circuits.get(&key).and_then(|c| c.open_until) {
+        Some(until) if breaker.config.is_some() && until > now() => {
+            Err(CircuitOpen { host: key, until })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn record(url: &Url, ok: bool) {
+    let mut breaker = BREAKER.lock().unwrap();
+    let config = match &breaker.config {
+        Some(config) => config.clone(),
+        None => return,
+    };
+    let key = circuit_key(url);
+    if ok {
+        if let Some(circuit) = breaker.circuits.remove(&key) {
+            if circuit.open_until.is_some() {
+                info!("{} is back, circuit closed", key);
+            }
+        }
+        return;
+    }
+    let circuit = breaker.circuits.entry(key.clone()).or_default();
+    circuit.failures += 1;
+    // once open, a failed retry after the cool down opens it again
+    if circuit.failures >= config.failures {
+        if circuit.open_until.is_none() {
+            warn!(
+                "{} failed {} times in a row, circuit open for {}s",
+                key, circuit.failures, config.cool_down
+            );
+        }
+        circuit.open_until = Some(now() + config.cool_down);
+    }
+}
+
+/// Sends `req` to `url` through the circuit breaker. Only connection
+/// errors and server errors count as failures, a 404 says nothing about
+/// the host.
+pub async fn send(req: reqwest::RequestBuilder, url: &Url) -> anyhow::Result<reqwest::Response> {
+    check_circuit(url)?;
+    let resp = req.send().await;
+    let ok = match &resp {
+        Ok(resp) => {
+            !resp.status().is_server_error()
+                && resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        Err(_) => false,
+    };
+    record(url, ok);
+    Ok(resp?)
+}
+
+fn is_media_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("image/") || mime.starts_with("video/") || mime == "application/octet-stream"
+}
+
+fn has_media_magic(data: &[u8]) -> bool {
+    const MAGIC: &[&[u8]] = &[b"\xFF\xD8\xFF", b"\x89PNG", b"GIF8", b"BM"];
+    MAGIC.iter().any(|m| data.starts_with(m))
+        || data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP")
+        || data.get(4..8) == Some(b"ftyp")
+}
+
+pub struct Download {
+    /// URL after following redirects
+    pub url: Url,
+    pub data: Vec<u8>,
+}
+
+fn host_in(url: &Url, hosts: &[&str]) -> bool {
+    url.host_str().is_some_and(|host| {
+        hosts
+            .iter()
+            .any(|h| host == *h || host.ends_with(&format!(".{}", h)))
+    })
+}
+
+fn needs_jandan_referer(url: &Url) -> bool {
+    host_in(url, JANDAN_REFERER_HOSTS)
+}
+
+fn is_allowed(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https") && host_in(url, ALLOWED_HOSTS)
+}
+
+/// One line per download attempt and redirect, kept with
+/// `RUST_LOG=download=debug`, to tell afterwards why an image fell back to
+/// its URL
+fn log_attempt(requested: &Url, url: &Url, resp: &anyhow::Result<reqwest::Response>) {
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            debug!(target: "download", "requested={} url={} error={:#}", requested, url, e);
+            return;
+        }
+    };
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+    let content_length = resp
+        .content_length()
+        .map_or_else(|| "-".to_owned(), |len| len.to_string());
+    debug!(
+        target: "download",
+        "requested={} url={} status={} content_type={} content_length={}",
+        requested,
+        url,
+        resp.status().as_u16(),
+        content_type,
+        content_length
+    );
+}
+
+/// Downloads media the way a browser on jandan would: hotlink protected
+/// hosts get the jandan referer, everything else the previous hop.
+pub async fn download(url: &str) -> anyhow::Result<Download> {
+    fetch(url, None).await
+}
+
+/// Like `download` with more time, stops reading once the body is known to
+/// be over `limit` bytes
+pub async fn download_video(url: &str, limit: usize) -> anyhow::Result<Download> {
+    fetch(url, Some(limit)).await
+}
+
+async fn fetch(url: &str, limit: Option<usize>) -> anyhow::Result<Download> {
+    let requested = Url::parse(url)?;
+    let rule = {
+        let rules = REWRITES.lock().unwrap();
+        rewrite(&rules, url).map(|(url, rule)| (url, rule.clone()))
+    };
+    let (mut url, rule) = match rule {
+        Some((rewritten, rule)) => (Url::parse(&rewritten)?, Some(rule)),
+        None => (requested.clone(), None),
+    };
+    // the host a rule points to is trusted like the built-in ones
+    let rule_host = rule
+        .as_ref()
+        .and_then(|rule| Url::parse(&rule.to).ok())
+        .and_then(|to| to.host_str().map(str::to_owned));
+    let in_rule_host = |url: &Url| rule_host.is_some() && url.host_str() == rule_host.as_deref();
+    let rule_referer = |url: &Url| {
+        rule.as_ref()
+            .and_then(|rule| rule.referer.clone())
+            .filter(|_| in_rule_host(url))
+    };
+    let mut referer = rule_referer(&url).unwrap_or_else(|| JANDAN_REFERER.to_owned());
+    for _ in 0..=MAX_REDIRECTS {
+        if !is_allowed(&url) && !in_rule_host(&url) {
+            return Err(ForbiddenHost { url }.into());
+        }
+        let mut req = DOWNLOAD_CLIENT.with(|client| {
+            client
+                .get(url.clone())
+                .header(header::REFERER, referer.as_str())
+        });
+        if limit.is_some() {
+            req = req.timeout(VIDEO_TIMEOUT);
+        }
+        let resp = send(req, &url).await;
+        log_attempt(&requested, &url, &resp);
+        let resp = resp?;
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(header::LOCATION)
+                .context("redirect without location")?
+                .to_str()?;
+            let next = url.join(location)?;
+            referer = if let Some(referer) = rule_referer(&next) {
+                referer
+            } else if needs_jandan_referer(&next) {
+                JANDAN_REFERER.to_owned()
+            } else {
+                url.to_string()
+            };
+            url = next;
+            continue;
+        }
+        let resp = resp.error_for_status()?;
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+        // skip the body of obvious error pages
+        if content_type
+            .as_deref()
+            .is_some_and(|t| t.starts_with("text/"))
+        {
+            return Err(WrongContentType { url, content_type }.into());
+        }
+        let data = match limit {
+            Some(limit) => read_limited(resp, limit).await?,
+            None => resp.bytes().await?.to_vec(),
+        };
+        if !content_type.as_deref().is_some_and(is_media_type) && !has_media_magic(&data) {
+            return Err(WrongContentType { url, content_type }.into());
+        }
+        return Ok(Download { url, data });
+    }
+    bail!("too many redirects, last one to {}", url)
+}
+
+async fn read_limited(mut resp: reqwest::Response, limit: usize) -> anyhow::Result<Vec<u8>> {
+    if let Some(len) = resp.content_length().filter(|&len| len > limit as u64) {
+        bail!("{} bytes is too large to upload", len);
+    }
+    // the length can be missing or wrong
+    let mut data = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if data.len() + chunk.len() > limit {
+            bail!("more than {} bytes is too large to upload", limit);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker() {
+        let url = Url::parse("https://wx1.sinaimg.cn/large/a.jpg").unwrap();
+        let other = Url::parse("https://wx2.sinaimg.cn/large/b.jpg").unwrap();
+        init_breaker(
+            Some(BreakerConfig {
+                failures: 2,
+                cool_down: 60,
+            }),
+            HashMap::new(),
+        );
+        record(&url, false);
+        assert!(check_circuit(&other).is_ok());
+        record(&url, false);
+        assert!(check_circuit(&other).is_err());
+        assert!(circuits()["sinaimg.cn"].open_until.is_some());
+        record(&url, true);
+        assert!(check_circuit(&other).is_ok());
+        assert!(circuits().is_empty());
+    }
+
+    #[test]
+    fn rewrites() {
+        let rules = vec![Rewrite {
+            from: "https://img.toto.im/".into(),
+            to: "https://wx1.sinaimg.cn/".into(),
+            referer: None,
+        }];
+        let (url, _) = rewrite(&rules, "https://img.toto.im/large/a.jpg").unwrap();
+        assert_eq!(url, "https://wx1.sinaimg.cn/large/a.jpg");
+        assert!(rewrite(&rules, "https://moyu.im/large/a.jpg").is_none());
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        let allowed = |url| is_allowed(&Url::parse(url).unwrap());
+        assert!(allowed("https://wx1.sinaimg.cn/large/a.jpg"));
+        assert!(allowed("http://jandan.net/a.gif"));
+        assert!(!allowed("http://127.0.0.1/a.jpg"));
+        assert!(!allowed("http://evilsinaimg.cn/a.jpg"));
+        assert!(!allowed("file:///etc/passwd"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::error;
+use tbot::types::message;
+
+/// Channel messages of the post being sent are kept here until the post is
+/// complete, a file left behind belongs to a post that died halfway
+pub const DIR: &str = "journal";
+
+lazy_static! {
+    static ref CURRENT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// A post that was never completed, with the messages it left in `chat`
+pub struct Orphan {
+    file: String,
+    pub section: String,
+    pub post_id: String,
+    pub chat: String,
+    pub messages: Vec<message::Id>,
+}
+
+/// Starts a journal for a post, an earlier failed attempt at the same post
+/// keeps its own file
+pub fn begin(chat: &str, section: &str, post_id: &str) -> io::Result<()> {
+    fs::create_dir_all(DIR)?;
+    let path = PathBuf::from(DIR).join(format!(
+        "{}-{}-{}",
+        section,
+        post_id,
+        crate::database::now()
+    ));
+    fs::write(&path, format!("{}\n", chat))?;
+    *CURRENT.lock().unwrap() = Some(path);
+    Ok(())
+}
+
+/// Notes a message sent to the channel for the current post
+pub fn record(id: message::Id) {
+    let current = CURRENT.lock().unwrap();
+    let path = match &*current {
+        Some(path) => path,
+        None => return,
+    };
+    let result = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{}", id.0));
+    if let Err(e) = result {
+        error!("journal {}: {}", path.display(), e);
+    }
+}
+
+/// Ends the journal of the current post, returning its messages
+pub fn finish() -> io::Result<Vec<message::Id>> {
+    match CURRENT.lock().unwrap().take() {
+        Some(path) => {
+            let (_, messages) = parse(&fs::read_to_string(&path)?);
+            fs::remove_file(path)?;
+            Ok(messages)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The chat and the messages sent to it
+fn parse(s: &str) -> (String, Vec<message::Id>) {
+    let mut lines = s.lines();
+    let chat = lines.next().unwrap_or_default().to_owned();
+    let messages = lines
+        .filter_map(|l| l.trim().parse().ok())
+        .map(message::Id)
+        .collect();
+    (chat, messages)
+}
+
+pub fn orphans() -> io::Result<Vec<Orphan>> {
+    let dir = match fs::read_dir(DIR) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut orphans = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        let (chat, messages) = parse(&fs::read_to_string(entry.path())?);
+        let file = entry.file_name().to_string_lossy().into_owned();
+        let (section, post_id) = parse_name(&file);
+        orphans.push(Orphan {
+            section: section.to_owned(),
+            post_id: post_id.to_owned(),
+            file,
+            chat,
+            messages,
+        });
+    }
+    Ok(orphans)
+}
+
+/// `(section, post ID)` of a journal file, files from before sections are
+/// named `<post ID>-<time>`
+fn parse_name(file: &str) -> (&str, &str) {
+    let name = file.rsplit_once('-').map_or(file, |(name, _)| name);
+    name.split_once('-')
+        .unwrap_or((crate::spider::SECTION, name))
+}
+
+pub fn forget(orphan: &Orphan) -> io::Result<()> {
+    fs::remove_file(PathBuf::from(DIR).join(&orphan.file))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(parse_name("ooxx-42-1000"), ("ooxx", "42"));
+        assert_eq!(parse_name("42-1000"), ("pic", "42"));
+    }
+}
@@ -1,30 +1,68 @@
 #![feature(iter_intersperse)]
 
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::fs;
 use std::io::Cursor;
-use std::time::Duration;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use convert::video_to_mp4;
 use futures::prelude::*;
-use log::error;
+use log::{error, info, warn};
+use markup::{Markup, QUOTE_END, QUOTE_START};
+use sha2::{Digest, Sha256};
 use tbot::types::{
-    input_file::{Document, GroupMedia, Photo, Video},
+    chat,
+    input_file::{Animation, Document, GroupMedia, Photo, Video},
+    message,
     parameters::{ChatId, Text},
 };
+use unicode_segmentation::UnicodeSegmentation;
 
+mod archive_box;
+mod archiver;
+mod bot_api;
+mod bot_error;
 mod convert;
 mod database;
+mod history;
+mod http;
+mod journal;
+mod markup;
+mod preflight;
+mod preprocess;
+mod run_lock;
 mod spider;
+mod throttle;
+mod update_check;
 mod wayback_machine;
 
 const HISTORY_SIZE: usize = 100;
 const HISTORY_FILE: &str = "history.text";
+const DB_FILE: &str = "db.json";
 const TG_IMAGE_DIMENSION_LIMIT: u32 = 1280;
 const TG_IMAGE_SIZE_LIMIT: usize = 10 * 1000 * 1000;
+/// Bots can't upload larger files
+const TG_VIDEO_SIZE_LIMIT: usize = 50 * 1000 * 1000;
 const LOW_QUALITY_IMG_SIZE: usize = 200 * 1024;
 const TG_CAPTION_LIMIT: usize = 1024;
+const TG_MESSAGE_LIMIT: usize = 4096;
+const TG_ALBUM_LIMIT: usize = 10;
+const ARCHIVE_MAX_ATTEMPTS: u32 = 5;
+const TRUNCATED_NOTE: &str = "［动图过长，已截断］";
+const GIF_FAILED_NOTE: &str = "动图转换失败，点击原链接查看";
+const IMAGE_FAILED_NOTE: &str = "图片加载失败，点击原链接查看";
+const SCREENSHOT_NOTE: &str = "图片均加载失败，以上为帖子截图";
+const DELETED_NOTE: &str = "原帖已删除";
+const STATS_TOP_AUTHORS: usize = 5;
+const AUTHOR_NAME_LIMIT: usize = 24;
+const QUOTE_LIMIT: usize = 80;
+const CONTINUED_NOTE: &str = "(续)";
+const COMMENT_DOWNLOAD_CONCURRENCY: usize = 4;
+const COMMENT_FETCH_CONCURRENCY: usize = 4;
 
 struct Image {
     format: image::ImageFormat,
@@ -32,6 +70,8 @@ struct Image {
     width: u32,
     height: u32,
     data: Vec<u8>,
+    /// Where the image was downloaded from after redirects
+    url: String,
 }
 
 impl Image {
@@ -40,21 +80,29 @@ impl Image {
     }
 }
 
+fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// SHA-256 of `data` with the extension of `original`, the file names
+/// on jandan's image hosts say nothing about the image
+fn content_name(data: &[u8], original: &str) -> String {
+    let hash = content_hash(data);
+    match original.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() => format!("{}.{}", hash, ext),
+        _ => hash,
+    }
+}
+
 async fn download_image(url: &str) -> anyhow::Result<Image> {
     let url = reqwest::Url::parse(url)?;
-    let name = url
+    let original = url
         .path_segments()
-        .map(|s| s.last())
-        .flatten()
+        .and_then(|mut s| s.next_back())
         .unwrap_or_default()
-        .into();
-    let buf = spider::CLIENT
-        .with(|client| client.get(url).header("referer", "https://jandan.net/"))
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?;
+        .to_owned();
+    let http::Download { url, data: buf } = http::download(url.as_str()).await?;
+    let name = content_name(&buf, &original);
     let reader = image::io::Reader::new(Cursor::new(&buf))
         .with_guessed_format()
         .expect("io read error in Cursor<Vec>?");
@@ -67,7 +115,8 @@ async fn download_image(url: &str) -> anyhow::Result<Image> {
         name,
         width: dimensions.0,
         height: dimensions.1,
-        data: buf.to_vec(),
+        data: buf,
+        url: url.to_string(),
     })
 }
 
@@ -79,173 +128,1475 @@ fn telegram_md_escape(s: &str) -> String {
         .replace("`", "\\`")
 }
 
+/// Legacy Markdown can't escape inside an entity, markdown characters in
+/// there are removed
+fn strip_md(s: &str) -> String {
+    s.chars().filter(|c| !"*_`[]".contains(*c)).collect()
+}
+
+/// `s` in between a pair of `marker`, whitespace around it stays outside
+fn md_entity(marker: char, s: &str) -> String {
+    let s = strip_md(s);
+    let inner = s.trim();
+    if inner.is_empty() {
+        return s;
+    }
+    let start = s.len() - s.trim_start().len();
+    let end = start + inner.len();
+    format!("{}{}{}{}{}", &s[..start], marker, inner, marker, &s[end..])
+}
+
+/// Makes an author name safe to put inside `*bold*`: markdown characters are
+/// removed, whitespace is collapsed and long names are truncated.
+fn sanitize_author(name: &str) -> String {
+    let name = name
+        .split(|c: char| c.is_whitespace() || "*_`[]".contains(c))
+        .filter(|s| !s.is_empty())
+        .intersperse(" ")
+        .collect::<String>();
+    truncate(&name, AUTHOR_NAME_LIMIT)
+}
+
+/// Cuts `s` to `limit` graphemes, marking the cut with "…"
+fn truncate(s: &str, limit: usize) -> String {
+    let mut graphemes = s.graphemes(true);
+    let mut r: String = graphemes.by_ref().take(limit).collect();
+    if graphemes.next().is_some() {
+        r.push('…');
+    }
+    r
+}
+
+struct Args {
+    /// A subcommand and its arguments, or the wayback machine token for a
+    /// normal run
+    positional: Vec<String>,
+    channel_override: Option<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut r = Args {
+            positional: Vec::new(),
+            channel_override: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--channel-override" => {
+                    let chat = args.next().context("--channel-override needs a chat")?;
+                    r.channel_override = Some(chat);
+                }
+                _ => r.positional.push(arg),
+            }
+        }
+        Ok(r)
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let wayback_machine_token = std::env::args().nth(1);
+    let args = Args::parse(std::env::args().skip(1))?;
+
+    // containers keep their state on a volume, relative paths point there
+    if let Some(dir) = std::env::var_os("DATA_DIR") {
+        std::env::set_current_dir(&dir)
+            .with_context(|| format!("DATA_DIR {}", Path::new(&dir).display()))?;
+    }
+    let db_file = env_path("DB_PATH", DB_FILE);
+    let mut db = database::Database::open(&db_file).await?;
+    let wayback_machine_token = match args.positional.as_slice() {
+        [cmd] if cmd == "version" => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), update_check::VERSION);
+            return Ok(());
+        }
+        [cmd] if cmd == "status" => {
+            print_status(&db.last_run);
+            for (host, circuit) in &db.circuits {
+                if let Some(until) = circuit.open_until {
+                    println!(
+                        "{}: {} failures, circuit open until {}s from now",
+                        host,
+                        circuit.failures,
+                        until.saturating_sub(database::now())
+                    );
+                }
+            }
+            return Ok(());
+        }
+        [cmd, id] if cmd == "preview" => return preview(&db, id).await,
+        [cmd] if cmd == "cleanup" => return cleanup(&db).await,
+        [cmd, id] if cmd == "lookup" => {
+            print_lookup(&db, id.parse().context("lookup <comment id>")?);
+            return Ok(());
+        }
+        [cmd] if cmd == "stats" => {
+            print_stats(&db, 7);
+            return Ok(());
+        }
+        [cmd, days] if cmd == "stats" => {
+            print_stats(&db, days.parse().context("stats <days>")?);
+            return Ok(());
+        }
+        [] => None,
+        [token] => Some(token.clone()),
+        _ => anyhow::bail!("unexpected arguments: {:?}", args.positional),
+    };
+    let _lock = match &db.run_lock {
+        Some(config) => match run_lock::acquire(config)? {
+            Some(lock) => {
+                // the instance that had the lock may have changed it
+                db = database::Database::open(&db_file).await?;
+                Some(lock)
+            }
+            None => {
+                info!("another instance is running, exiting");
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    if let Some(chat) = args.channel_override {
+        db.set_channel_override(chat);
+    }
+    http::init_breaker(db.circuit_breaker.clone(), std::mem::take(&mut db.circuits));
+    http::init_rewrites(db.image_rewrites.clone());
+
+    db.last_run.start();
+    let result =
+        run(&mut db, wayback_machine_token)
+            .await
+            .map_err(|e| match bot_error::BotError::of(&e) {
+                Some(kind) => e.context(kind),
+                None => e,
+            });
+    db.last_run.finish(result.as_ref().err());
+    db.circuits = http::circuits();
+    db.save().await?;
+    result
+}
 
-    let mut db = database::Database::open("db.json").await?;
+/// Deletes the messages of posts that failed halfway
+async fn cleanup(db: &database::Database) -> anyhow::Result<()> {
     let bot = tbot::Bot::new(db.token.clone());
-    let history = fs::read_to_string(HISTORY_FILE)?;
-    let history: Vec<&str> = history.lines().collect();
-    let pics = spider::do_the_evil().await?;
-    let mut fresh_imgs: Vec<Cow<str>> = Vec::with_capacity(HISTORY_SIZE);
+    for orphan in journal::orphans()? {
+        for id in &orphan.messages {
+            throttle::wait(ChatId::from(&*orphan.chat), 1).await;
+            if let Err(e) = bot
+                .delete_message(ChatId::from(&*orphan.chat), *id)
+                .call()
+                .await
+            {
+                // already deleted by hand, or too old to be deleted by bots
+                error!("{}: delete {}: {}", orphan.post_id, id.0, e);
+            }
+        }
+        println!(
+            "{}: deleted {} messages",
+            orphan.post_id,
+            orphan.messages.len()
+        );
+        journal::forget(&orphan)?;
+    }
+    Ok(())
+}
+
+fn print_status(run: &database::RunInfo) {
+    let now = database::now();
+    if run.started_at == 0 {
+        println!("never ran");
+        return;
+    }
+    println!(
+        "last run started {}s ago",
+        now.saturating_sub(run.started_at)
+    );
+    match run.finished_at {
+        Some(t) if run.succeeded => println!("succeeded {}s ago", now.saturating_sub(t)),
+        Some(t) => println!("failed {}s ago", now.saturating_sub(t)),
+        None => println!("still running or killed"),
+    }
+    println!("posted: {}", run.posted);
+    if let Some(endpoint) = &run.endpoint {
+        println!("jandan endpoint: {}", endpoint);
+    }
+    if let Some(t) = run.last_success_at {
+        println!("last success: {}s ago", now.saturating_sub(t));
+    }
+    if let (Some(t), Some(e)) = (run.last_error_at, &run.last_error) {
+        println!("last error ({}s ago): {}", now.saturating_sub(t), e);
+    }
+    if let Some(t) = run.paused_since {
+        println!(
+            "paused {}s ago, no access to {}",
+            now.saturating_sub(t),
+            run.paused_chat.as_deref().unwrap_or("the channel")
+        );
+    }
+}
 
-    for pic in pics.into_iter().filter(|pic| !history.contains(&&*pic.id)) {
-        upload_comment_images(&bot, &mut db, &pic.comments).await?;
-        upload_comment_mentions(&bot, &mut db, &pic.comments).await?;
-        send_pic(&bot, &db, &pic).await?;
+/// Where a jandan comment ended up on telegram
+fn print_lookup(db: &database::Database, comment_id: u64) {
+    let post = db.get_posted_comment(comment_id);
+    let mirror = db.get_comment(comment_id);
+    if let Some(link) = &post {
+        println!("post: {}", link);
+    }
+    if let Some(link) = &mirror {
+        println!("mirror: {}", link);
+    }
+    if post.is_none() && mirror.is_none() {
+        println!("comment {} was never sent", comment_id);
+    }
+}
 
-        fresh_imgs.push(pic.id.into());
+/// Sums up the posts of the last `days` days
+fn print_stats(db: &database::Database, days: u64) {
+    let since = database::now().saturating_sub(days * 24 * 60 * 60);
+    let posts: Vec<_> = db.stats.iter().filter(|p| p.posted_at >= since).collect();
+    println!("posts in the last {} days: {}", days, posts.len());
+    if posts.is_empty() {
+        return;
+    }
+    println!("per day: {:.1}", posts.len() as f64 / days.max(1) as f64);
+    let oo: u64 = posts.iter().map(|p| p.oo as u64).sum();
+    let xx: u64 = posts.iter().map(|p| p.xx as u64).sum();
+    println!(
+        "average OO: {:.1} XX: {:.1}",
+        oo as f64 / posts.len() as f64,
+        xx as f64 / posts.len() as f64
+    );
+    let gifs: usize = posts.iter().map(|p| p.gifs).sum();
+    let failed: usize = posts.iter().map(|p| p.failed_gifs).sum();
+    if gifs > 0 {
+        println!(
+            "GIF conversion failures: {}/{} ({:.1}%)",
+            failed,
+            gifs,
+            failed as f64 * 100.0 / gifs as f64
+        );
     }
+    let mut authors: HashMap<&str, usize> = HashMap::new();
+    for p in &posts {
+        *authors.entry(&p.author).or_default() += 1;
+    }
+    let mut authors: Vec<_> = authors.into_iter().collect();
+    authors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("top authors:");
+    for (author, count) in authors.iter().take(STATS_TOP_AUTHORS) {
+        println!("  {}: {}", author, count);
+    }
+}
+
+/// Where new posts are read from
+fn source(db: &database::Database) -> Box<dyn spider::Source> {
+    Box::new(spider::Jandan::new(
+        db.endpoints.clone(),
+        db.section_names(),
+        db.max_pages,
+    ))
+}
 
+/// Renders a post into `preview/<id>/` without talking to Telegram.
+async fn preview(db: &database::Database, id: &str) -> anyhow::Result<()> {
+    let post_id = id
+        .parse()
+        .with_context(|| format!("invalid post id {}", id))?;
+    // the front page lists of the configured sections tell the post's
+    // section, and find an endpoint that answers on the way
+    let mut source = source(db);
+    let listed: Vec<spider::Pic> = source.posts(&|_, _| true).try_collect().await?;
+    let pic = match listed.into_iter().find(|pic| pic.id == id) {
+        Some(mut pic) => {
+            if !db.skips_comments(&pic.section) {
+                source.load_comments(&mut pic).await?;
+            }
+            pic
+        }
+        // its own page doesn't tell the section
+        None => source.post(post_id).await?,
+    };
+    let dir = Path::new("preview").join(id);
+    fs::create_dir_all(&dir)?;
     fs::write(
-        HISTORY_FILE,
-        fresh_imgs
-            .iter()
-            .map(|s| &**s)
-            .chain(history.into_iter())
-            .take(HISTORY_SIZE)
-            .intersperse("\n".into())
-            .collect::<String>(),
+        dir.join("caption.md"),
+        format_caption(db, &pic, false).join("\n\n---\n\n"),
     )?;
+    let overlay = vote_overlay(db, &pic);
+    for (i, url) in pic.images.iter().enumerate() {
+        match download_image(url).await {
+            Ok(img) if img.is_gif() => {
+                let mp4 = video_to_mp4(img.data, &db.convert, overlay.as_deref())?;
+                fs::write(dir.join(format!("{}-{}.mp4", i, img.name)), mp4.data)?;
+            }
+            Ok(img) => fs::write(dir.join(format!("{}-{}", i, img.name)), &img.data)?,
+            Err(e) => error!("{}: {}", url, e),
+        }
+    }
+    for (i, url) in pic.videos.iter().enumerate() {
+        match download_video(url).await {
+            Ok(data) => fs::write(
+                dir.join(format!("video-{}", i)),
+                prepare_video(db, url, data),
+            )?,
+            Err(e) => error!("{}: {}", url, e),
+        }
+    }
+    println!("{}", dir.display());
+    Ok(())
+}
 
-    if let Some(token) = wayback_machine_token {
-        wayback_machine::push(&token, &fresh_imgs).await?;
+/// A post of the catch-up queue that is no longer on the front page, None
+/// once it is deleted or censored
+async fn queued_post(
+    source: &dyn spider::Source,
+    db: &database::Database,
+    key: &history::Key,
+    now: u64,
+) -> Result<Option<spider::Pic>, spider::SpiderError> {
+    let id = key
+        .id
+        .parse()
+        .map_err(|_| spider::SpiderError::Parse(format!("post id {}", key.id)))?;
+    let mut pic = match source.post(id).await {
+        Ok(pic) if pic.is_censored() => return Ok(None),
+        Ok(pic) => pic,
+        Err(spider::SpiderError::Gone) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // its own page doesn't tell the section
+    pic.section = key.section.clone();
+    if db.skips_comments(&pic.section) {
+        pic.comments = Default::default();
+    }
+    drop_blocked_comments(&db.blocklist, &mut pic.comments);
+    db.comment_order.sort(&mut pic.comments.hot, now);
+    Ok(Some(pic))
+}
+
+async fn run(
+    db: &mut database::Database,
+    wayback_machine_token: Option<String>,
+) -> anyhow::Result<()> {
+    for blackout in &db.blackouts {
+        if blackout.contains(database::now())? {
+            info!(
+                "{}-{} is jandan's maintenance window, skipping",
+                blackout.start, blackout.end
+            );
+            return Ok(());
+        }
+    }
+    fs::create_dir_all(journal::DIR)?;
+    let history_file = env_path("HISTORY_PATH", HISTORY_FILE);
+    let dirs = [
+        parent_dir(&env_path("DB_PATH", DB_FILE)),
+        parent_dir(&history_file),
+        std::env::temp_dir(),
+        journal::DIR.into(),
+    ];
+    // better than failing halfway through a post
+    preflight::check(&dirs, db.min_free_mb)?;
+    let started = Instant::now();
+    let budget = db.max_run_duration.map(Duration::from_secs);
+    let out_of_time = || budget.is_some_and(|b| started.elapsed() >= b);
+    let bot = tbot::Bot::new(db.token.clone());
+    if let Some(config) = &db.update_check {
+        // never worth failing a run over
+        if let Err(e) = update_check::check(&bot, config, &mut db.release_check).await {
+            warn!("update check: {:#}", e);
+        }
+    }
+    if db.last_run.paused_since.is_some() {
+        // don't crawl jandan for nothing, getChat still works for a bot
+        // that is a member but lost the right to post
+        let chat = match &db.last_run.paused_chat {
+            Some(chat) => chat.clone(),
+            None => db.channel_name().to_owned(),
+        };
+        throttle::wait(ChatId::from(&*chat), 1).await;
+        let me = bot.get_me().call().await?;
+        throttle::wait(ChatId::from(&*chat), 1).await;
+        let member = bot
+            .get_chat_member(ChatId::from(&*chat), me.user.id)
+            .call()
+            .await
+            .with_context(|| format!("still no access to {}", chat))?;
+        if !can_post(&member.status) {
+            anyhow::bail!("still not allowed to post in {}", chat);
+        }
+        db.last_run.paused_since = None;
+        db.last_run.paused_chat = None;
+    }
+    let mut history = history::History::load(&history_file, db.is_new())?;
+    let now = database::now();
+    let mut source = source(db);
+    let scraped: Vec<spider::Pic> = source
+        .posts(&|section, id| history.contains(section, id))
+        .try_collect()
+        .await?;
+    db.last_run.endpoint = source.origin();
+    let (posted, pics): (Vec<_>, Vec<_>) = scraped
+        .into_iter()
+        .partition(|pic| history.contains(&pic.section, &pic.id));
+    let mut fresh: Vec<history::Key> = Vec::with_capacity(HISTORY_SIZE);
+    // seen but never sent, they only go into the history
+    let mut skipped: Vec<history::Key> = Vec::new();
+    let (blocked, pics): (Vec<_>, Vec<_>) = pics.into_iter().partition(|pic| {
+        let blocklist = &db.blocklist;
+        blocklist.blocks_author(&pic.author)
+            || blocklist.blocks_text(&pic.text)
+            || pic.alt_texts.iter().any(|t| blocklist.blocks_text(t))
+    });
+    for pic in &blocked {
+        info!("{}: blocked", pic.id);
+        // no need to look at it again
+        skipped.push(history::Key::new(&pic.section, &pic.id));
+    }
+    // tucao are only fetched for posts that are sent or refreshed, and not
+    // at all for channels that only want the pictures
+    let mut posted: Vec<_> = posted
+        .into_iter()
+        .filter(|pic| db.refresh_captions && db.tracked.iter().any(|t| t.is_due(pic, now)))
+        .collect();
+    let mut pics = pics;
+    let source = &*source;
+    let with_comments = pics
+        .iter_mut()
+        .chain(posted.iter_mut())
+        .filter(|pic| !db.skips_comments(&pic.section));
+    let mut gone: HashSet<history::Key> = stream::iter(with_comments)
+        .map(|pic| {
+            let key = history::Key::new(&pic.section, &pic.id);
+            source.load_comments(pic).map(move |r| (key, r))
+        })
+        .buffer_unordered(
+            db.comment_fetch_concurrency
+                .unwrap_or(COMMENT_FETCH_CONCURRENCY)
+                .max(1),
+        )
+        .filter_map(|(key, r)| {
+            future::ready(match r {
+                Ok(()) => None,
+                Err(spider::SpiderError::Gone) => Some(Ok(key)),
+                Err(e) => Some(Err(e)),
+            })
+        })
+        .try_collect()
+        .await?;
+    gone.extend(
+        pics.iter()
+            .filter(|pic| pic.is_censored())
+            .map(|pic| history::Key::new(&pic.section, &pic.id)),
+    );
+    let was_gone = |pic: &spider::Pic| gone.contains(&history::Key::new(&pic.section, &pic.id));
+    posted.retain(|pic| !was_gone(pic));
+    let (gone, mut pics): (Vec<_>, Vec<_>) = pics.into_iter().partition(|pic| was_gone(pic));
+    for pic in &gone {
+        if db.note_deleted {
+            db.set_section(&pic.section);
+            if let Err(e) = send_deleted_note(&bot, db, pic).await {
+                // tried again next run
+                error!("{}: deleted note: {:#}", pic.id, e);
+                continue;
+            }
+        }
+        info!("{}: deleted or censored", pic.id);
+        skipped.push(history::Key::new(&pic.section, &pic.id));
+    }
+    for pic in pics.iter_mut().chain(posted.iter_mut()) {
+        drop_blocked_comments(&db.blocklist, &mut pic.comments);
+        db.comment_order.sort(&mut pic.comments.hot, now);
+    }
+    let pics = if db.merge_multipart {
+        spider::merge_multipart(pics, TG_ALBUM_LIMIT)
+    } else {
+        pics
+    };
+    let pics: Vec<_> = match &db.vote_filter {
+        Some(filter) => pics
+            .into_iter()
+            .filter(|pic| {
+                let ok = filter.accepts(pic.oo, pic.xx);
+                if !ok {
+                    info!("{}: OO {} XX {}, not yet", pic.id, pic.oo, pic.xx);
+                }
+                ok
+            })
+            .collect(),
+        None => pics,
+    };
+    // a backlog waits in the queue of db.json, a run sends the oldest
+    // post once the interval is up
+    let catch_up = db.catch_up.as_ref().map(|c| (c.threshold, c.interval));
+    let pics = match catch_up {
+        Some((threshold, interval)) if pics.len() > threshold || !db.catch_up_queue.is_empty() => {
+            let mut pics = pics;
+            for pic in &pics {
+                let key = history::Key::new(&pic.section, &pic.id);
+                if !db.catch_up_queue.contains(&key) {
+                    // the queue remembers it from here on
+                    skipped.push(key.clone());
+                    db.catch_up_queue.push(key);
+                }
+            }
+            let due = db
+                .catch_up_released_at
+                .is_none_or(|at| now >= at + interval);
+            let mut released = Vec::new();
+            while let Some(key) = db.catch_up_queue.first().filter(|_| due).cloned() {
+                let listed = pics
+                    .iter()
+                    .position(|pic| pic.section == key.section && pic.id == key.id);
+                if let Some(i) = listed {
+                    released.push(pics.swap_remove(i));
+                    break;
+                }
+                match queued_post(source, db, &key, now).await {
+                    Ok(Some(pic)) => {
+                        released.push(pic);
+                        break;
+                    }
+                    Ok(None) => {
+                        info!("{}: deleted or censored while queued", key.id);
+                        db.catch_up_queue.remove(0);
+                    }
+                    Err(e) => {
+                        // tried again next run
+                        error!("{}: queued post: {}", key.id, e);
+                        break;
+                    }
+                }
+            }
+            info!("catch-up: {} posts queued", db.catch_up_queue.len());
+            released
+        }
+        _ => pics,
+    };
+
+    // only failures in the live channel count, test runs neither
+    // quarantine nor alert
+    let mut attempts: HashMap<history::Key, usize> = HashMap::new();
+    if !db.is_channel_overridden() {
+        for orphan in journal::orphans()? {
+            if orphan.chat != db.live_channel_name(&orphan.section) {
+                continue;
+            }
+            let key = history::Key::new(&orphan.section, &orphan.post_id);
+            *attempts.entry(key).or_default() += 1;
+        }
+    }
+
+    let total = pics.len();
+    for (i, pic) in pics.into_iter().enumerate() {
+        if out_of_time() {
+            warn!("out of time, {} posts left for the next run", total - i);
+            break;
+        }
+        let key = history::Key::new(&pic.section, &pic.id);
+        let failed = attempts.get(&key).copied().unwrap_or(0);
+        if let Some(quarantine) = db.quarantine.as_ref().filter(|q| failed >= q.after) {
+            error!("{}: failed {} times, skipping it for good", pic.id, failed);
+            if let Some(chat) = &quarantine.chat {
+                let text = format!(
+                    "https://jandan.net/t/{} failed {} runs, it won't be tried again. \
+                     The cleanup command deletes what the attempts left in the channel.",
+                    pic.id, failed
+                );
+                throttle::wait(chat.as_str().into(), 1).await;
+                if let Err(e) = bot
+                    .send_message(ChatId::from(&**chat), text.as_str())
+                    .call()
+                    .await
+                {
+                    error!("quarantine alert: {}", e);
+                }
+            }
+            for id in pic.parts.iter().chain(Some(&pic.id)) {
+                skipped.push(history::Key::new(&pic.section, id));
+            }
+            db.catch_up_queue.retain(|k| *k != key);
+            continue;
+        }
+        db.set_section(&pic.section);
+        let mut result = post(&bot, db, &pic).await;
+        let migrated = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref())
+            .and_then(migrated_to)
+            .is_some();
+        if migrated && !db.is_channel_overridden() {
+            follow_migrations(&bot, db).await?;
+            result = post(&bot, db, &pic).await;
+        }
+        let sent = match result {
+            Ok(sent) => sent,
+            Err(e) if lost_access(&e) => {
+                db.last_run.paused_chat = throttle::last_chat();
+                error!(
+                    "lost access to {}, pausing: {:#}",
+                    db.last_run.paused_chat.as_deref().unwrap_or("the channel"),
+                    e
+                );
+                db.last_run.paused_since = Some(database::now());
+                // keep what made it out, the rest waits for the next run
+                if !db.is_channel_overridden() {
+                    history.save(
+                        &history_file,
+                        &[&skipped[..], &fresh[..]].concat(),
+                        HISTORY_SIZE,
+                    )?;
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        db.record_stats(database::PostStats {
+            id: pic.id.clone(),
+            author: pic.author.clone(),
+            posted_at: database::now(),
+            oo: pic.oo,
+            xx: pic.xx,
+            gifs: sent.gifs,
+            failed_gifs: sent.failed_gifs,
+        });
+        if let Some(msg_id) = sent.ids.first() {
+            for comment in &pic.comments.hot {
+                db.put_posted_comment(comment.id, msg_id.0.into());
+            }
+        }
+        if db.refresh_captions && pic.parts.is_empty() {
+            db.tracked.push(sent.track(&pic));
+        }
+        if db.catch_up_queue.contains(&key) {
+            db.catch_up_queue.retain(|k| *k != key);
+            db.catch_up_released_at = Some(database::now());
+        }
+        // the post's assets, stats and tracking in one write
+        db.save().await?;
+
+        for id in pic.parts.iter().chain(Some(&pic.id)) {
+            fresh.push(history::Key::new(&pic.section, id));
+        }
+        db.last_run.posted += 1;
+    }
+
+    // test runs must not hide posts from the live channel
+    if !db.is_channel_overridden() {
+        history.save(
+            &history_file,
+            &[&skipped[..], &fresh[..]].concat(),
+            HISTORY_SIZE,
+        )?;
+    }
+
+    if db.refresh_captions && !out_of_time() {
+        // posts are out already, refresh problems shouldn't fail the run
+        if let Err(e) = refresh(&bot, db, &posted).await {
+            error!("refresh: {:#}", e);
+        }
+        db.save().await?;
+    }
+
+    let archiver = match (&db.archiver, wayback_machine_token) {
+        // test runs archive nothing, like they save no history
+        _ if db.is_channel_overridden() => None,
+        (Some(config), _) => Some(archiver::Archiver::new(config)),
+        (None, Some(token)) => Some(archiver::Archiver::new(&archiver::Config::WaybackMachine {
+            token,
+        })),
+        (None, None) => None,
+    };
+    if let Some(archiver) = archiver {
+        db.archive_queue
+            .extend(fresh.into_iter().map(|k| database::ArchiveJob::new(k.id)));
+        // posts are out already, archiving problems shouldn't fail the run
+        if out_of_time() {
+            warn!("out of time, archiving is left for the next run");
+        } else if let Err(e) = archive(db, &archiver).await {
+            error!("archive: {:#}", e);
+        }
+        db.save().await?;
     }
     Ok(())
 }
 
-async fn send_pic(
+async fn post(
+    bot: &tbot::Bot,
+    db: &mut database::Database,
+    pic: &spider::Pic,
+) -> anyhow::Result<Sent> {
+    journal::begin(db.channel_name(), &pic.section, &pic.id)?;
+    let mut sent = if db.post_before_assets {
+        let mut sent = send_pic(bot, db, pic).await?;
+        upload_comment_images(bot, db, &pic.comments).await?;
+        upload_comment_mentions(bot, db, &pic.comments).await?;
+        edit_captions(bot, db, pic, &mut sent).await?;
+        sent
+    } else {
+        upload_comment_images(bot, db, &pic.comments).await?;
+        upload_comment_mentions(bot, db, &pic.comments).await?;
+        send_pic(bot, db, pic).await?
+    };
+    sent.messages = journal::finish()?;
+    Ok(sent)
+}
+
+/// What is left of a post that went away before it was sent
+async fn send_deleted_note(
     bot: &tbot::Bot,
     db: &database::Database,
     pic: &spider::Pic,
 ) -> anyhow::Result<()> {
-    let images: Vec<Result<Image, (_, &str)>> = futures::stream::iter(&pic.images)
+    let text = format!(
+        "*{}*: https://jandan.net/t/{}\n{}\n*OO*: {} *XX*: {}",
+        sanitize_author(&pic.author),
+        pic.id,
+        DELETED_NOTE,
+        pic.oo,
+        pic.xx
+    );
+    let markup = Markup::new(&text);
+    throttle::wait(db.channel(), 1).await;
+    bot.send_message(db.channel(), markup.text())
+        .is_web_page_preview_disabled(true)
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Edits the captions of tracked posts that are still on the front page
+/// with their current votes and comments
+async fn refresh(
+    bot: &tbot::Bot,
+    db: &mut database::Database,
+    pics: &[spider::Pic],
+) -> anyhow::Result<()> {
+    let now = database::now();
+    db.tracked.retain(|t| t.next_refresh().is_some());
+    for pic in pics {
+        let due = db.tracked.iter().position(|t| t.is_due(pic, now));
+        let i = match due {
+            Some(i) => i,
+            None => continue,
+        };
+        upload_comment_images(bot, db, &pic.comments).await?;
+        upload_comment_mentions(bot, db, &pic.comments).await?;
+        db.set_section(&pic.section);
+        let mut sent = Sent::from(&db.tracked[i]);
+        edit_captions(bot, db, pic, &mut sent).await?;
+        if let Some(msg_id) = sent.ids.first() {
+            for comment in &pic.comments.hot {
+                db.put_posted_comment(comment.id, msg_id.0.into());
+            }
+        }
+        if let Some(chat) = db.best_of_channel() {
+            let min_oo = db.best_of.as_ref().map_or(0, |b| b.min_oo);
+            if !db.tracked[i].forwarded && pic.oo >= min_oo {
+                match forward(db, chat, &sent.messages).await {
+                    Ok(()) => db.tracked[i].forwarded = true,
+                    // the captions are still refreshed, forwarding is
+                    // tried again on the next refresh
+                    Err(e) => error!("{}: forward: {:#}", pic.id, e),
+                }
+            }
+        }
+        let tracked = &mut db.tracked[i];
+        tracked.captions = sent.captions;
+        tracked.refreshed_at = now;
+    }
+    Ok(())
+}
+
+/// Forwards a whole post, albums stay together
+async fn forward(
+    db: &database::Database,
+    chat: &str,
+    messages: &[message::Id],
+) -> anyhow::Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let mut ids: Vec<u32> = messages.iter().map(|id| id.0).collect();
+    // forwardMessages wants them in order
+    ids.sort_unstable();
+    throttle::wait(chat.into(), messages.len()).await;
+    bot_api::call_raw(
+        &db.token,
+        "forwardMessages",
+        &serde_json::json!({
+            "chat_id": chat,
+            "from_chat_id": db.channel_name(),
+            "message_ids": ids,
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+fn lost_access(e: &anyhow::Error) -> bool {
+    use bot_error::BotError;
+    matches!(
+        BotError::of(e),
+        Some(BotError::NotAdmin | BotError::ChatNotFound)
+    )
+}
+
+/// The new chat ID if the request failed because a group was upgraded to a
+/// supergroup
+fn migrated_to(e: &tbot::errors::MethodCall) -> Option<chat::Id> {
+    match e {
+        tbot::errors::MethodCall::RequestError {
+            migrate_to_chat_id, ..
+        } => *migrate_to_chat_id,
+        _ => None,
+    }
+}
+
+/// Whether a chat member with `status` can send posts
+fn can_post(status: &chat::member::Status) -> bool {
+    match status {
+        chat::member::Status::Creator { .. } => true,
+        // only set in channels, admins of groups can always post
+        chat::member::Status::Administrator {
+            can_post_messages, ..
+        } => can_post_messages.unwrap_or(true),
+        _ => false,
+    }
+}
+
+/// Asks telegram which of the configured chats moved and stores their new IDs
+async fn follow_migrations(bot: &tbot::Bot, db: &mut database::Database) -> anyhow::Result<()> {
+    throttle::wait(ChatId::from(&*db.channel), 1).await;
+    if let Some(id) = bot
+        .get_chat(ChatId::from(&*db.channel))
+        .call()
+        .await
+        .err()
+        .as_ref()
+        .and_then(migrated_to)
+    {
+        warn!("{} migrated to {}", db.channel, id.0);
+        db.channel = id.0.to_string();
+    }
+    throttle::wait(db.assets_channel(), 1).await;
+    if let Some(id) = bot
+        .get_chat(db.assets_channel())
+        .call()
+        .await
+        .err()
+        .as_ref()
+        .and_then(migrated_to)
+    {
+        warn!("{} migrated to {}", db.assets_channel, id.0);
+        db.assets_channel = id.0.to_string();
+    }
+    if let Some(channel) = &db.mentions_channel {
+        throttle::wait(ChatId::from(&**channel), 1).await;
+        let migrated = bot
+            .get_chat(ChatId::from(&**channel))
+            .call()
+            .await
+            .err()
+            .as_ref()
+            .and_then(migrated_to);
+        if let Some(id) = migrated {
+            warn!("{} migrated to {}", channel, id.0);
+            db.mentions_channel = Some(id.0.to_string());
+        }
+    }
+    for i in 0..db.sections.len() {
+        let channel = match &db.sections[i].channel {
+            Some(channel) => channel,
+            None => continue,
+        };
+        throttle::wait(ChatId::from(&**channel), 1).await;
+        let migrated = bot
+            .get_chat(ChatId::from(&**channel))
+            .call()
+            .await
+            .err()
+            .as_ref()
+            .and_then(migrated_to);
+        if let Some(id) = migrated {
+            warn!("{} migrated to {}", channel, id.0);
+            db.sections[i].channel = Some(id.0.to_string());
+        }
+    }
+    db.save().await
+}
+
+/// The path in the environment variable `var`, `default` without it
+fn env_path(var: &str, default: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| default.to_owned())
+}
+
+fn parent_dir(file: &str) -> std::path::PathBuf {
+    match Path::new(file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.into(),
+        _ => ".".into(),
+    }
+}
+
+/// Works through the archive queue until the archiver runs out of capacity,
+/// whatever is left is retried by the next run.
+async fn archive(db: &mut database::Database, archiver: &archiver::Archiver) -> anyhow::Result<()> {
+    while let Some(job) = db.archive_queue.first_mut() {
+        if !archiver.has_capacity().await? {
+            break;
+        }
+        let url = format!("https://jandan.net/t/{}", job.id);
+        match archiver.save(&url).await {
+            Ok(()) => {
+                db.archive_queue.remove(0);
+            }
+            Err(e) => {
+                error!("archive {}: {:#}", job.id, e);
+                job.attempts += 1;
+                let job = db.archive_queue.remove(0);
+                if job.attempts < ARCHIVE_MAX_ATTEMPTS {
+                    db.archive_queue.push(job);
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Messages carrying the captions of a sent post, `ids[i]` has `captions[i]`
+struct Sent {
+    ids: Vec<message::Id>,
+    /// the first caption is attached to a photo/video/document
+    media_caption: bool,
+    captions: Vec<String>,
+    /// notes added to the captions while sending, see `add_note`
+    notes: Vec<String>,
+    /// GIFs among the downloaded images, by their decoded format
+    gifs: usize,
+    /// GIFs that were sent as a still frame
+    failed_gifs: usize,
+    /// Every message of the post, filled in once the post is complete
+    messages: Vec<message::Id>,
+}
+
+impl Sent {
+    fn track(self, pic: &spider::Pic) -> database::Tracked {
+        let now = database::now();
+        database::Tracked {
+            section: pic.section.clone(),
+            id: pic.id.clone(),
+            posted_at: now,
+            refreshed_at: now,
+            messages: self.ids.iter().map(|id| id.0).collect(),
+            media_caption: self.media_caption,
+            captions: self.captions,
+            notes: self.notes,
+            post_messages: self.messages.iter().map(|id| id.0).collect(),
+            forwarded: false,
+        }
+    }
+}
+
+impl From<&database::Tracked> for Sent {
+    fn from(t: &database::Tracked) -> Self {
+        Sent {
+            ids: t.messages.iter().copied().map(message::Id).collect(),
+            media_caption: t.media_caption,
+            captions: t.captions.clone(),
+            notes: t.notes.clone(),
+            gifs: 0,
+            failed_gifs: 0,
+            messages: t.post_messages.iter().copied().map(message::Id).collect(),
+        }
+    }
+}
+
+async fn send_pic(
+    bot: &tbot::Bot,
+    db: &database::Database,
+    pic: &spider::Pic,
+) -> anyhow::Result<Sent> {
+    // errors tell whether the thumbnail was tried already
+    let images: Vec<Result<Image, (_, &str, bool)>> = futures::stream::iter(&pic.images)
         .then(|url| async move {
-            for n in (0..3).rev() {
-                match download_image(url).await {
+            let mut candidate = url.clone();
+            let mut retries = 2;
+            loop {
+                match download_image(&candidate).await {
                     Ok(r) => return Ok(r),
-                    Err(e) if n == 0 => {
-                        return Err((e, url.as_str()));
+                    // sometimes only `large/` is referer blocked, the smaller
+                    // size is tried right away
+                    Err(e) if is_forbidden(&e) && thumbnail_url(&candidate).is_some() => {
+                        let smaller = thumbnail_url(&candidate).expect("checked above");
+                        info!("{}: {:#}, trying {}", candidate, e, smaller);
+                        candidate = smaller;
+                    }
+                    Err(e)
+                        if retries == 0
+                            || e.is::<http::WrongContentType>()
+                            || e.is::<http::ForbiddenHost>()
+                            || e.is::<http::CircuitOpen>() =>
+                    {
+                        return Err((e, url.as_str(), candidate != *url));
                     }
                     Err(_e) => {
+                        retries -= 1;
                         tokio::time::delay_for(Duration::from_secs(1)).await;
                     }
                 }
             }
-            unreachable!()
         })
         .collect()
         .await;
 
-    let captions = format_caption(db, pic);
-    let mut captions = captions
-        .iter()
-        .map(String::as_str)
-        .map(Text::with_markdown)
-        .collect();
+    let images = match db.max_images {
+        Some(max) if images.len() > max => keep_largest(images, max),
+        _ => images,
+    };
+
+    let images = match &db.upscale {
+        Some(options) => images
+            .into_iter()
+            .map(|r| r.map(|img| upscale_if_small(img, options)))
+            .collect(),
+        None => images,
+    };
+
+    let mut strips = Vec::new();
+    let images = match db.long_strip_ratio {
+        Some(ratio) => split_long_strips(images, ratio, &mut strips),
+        None => images,
+    };
+
+    let mut notes = Vec::new();
+    let mut r = Vec::with_capacity(images.len());
+    for img in images {
+        r.push(match img {
+            // a thumbnail that failed already won't make a placeholder
+            Err((e, url, false)) if db.failed_image_placeholders => match placeholder(url).await {
+                Ok(p) => {
+                    error!("{}: {}, sending a placeholder", url, e);
+                    notes.push(image_failed_note(url));
+                    Ok(p)
+                }
+                Err(_) => Err((e, url)),
+            },
+            Err((e, url, _)) => Err((e, url)),
+            Ok(img) => Ok(img),
+        });
+    }
+    let images = r;
+
+    let images = match &db.screenshot_command {
+        Some(command) if !images.is_empty() && images.iter().all(|r| r.is_err()) => {
+            let page = format!("https://jandan.net/t/{}", pic.id);
+            match preprocess::screenshot(command, &page) {
+                Ok(img) => {
+                    notes.push(SCREENSHOT_NOTE.to_owned());
+                    vec![Ok(img)]
+                }
+                Err(e) => {
+                    error!("{}: screenshot failed: {}", pic.id, e);
+                    images
+                }
+            }
+        }
+        _ => images,
+    };
+
+    let mut captions = format_caption(db, pic, db.post_before_assets);
+    for note in &notes {
+        add_note(&mut captions, note);
+    }
+    let overlay = vote_overlay(db, pic);
     let contains_error = images.iter().any(|r| r.is_err());
     let contains_large_image = images
         .iter()
         .filter_map(|r| r.as_ref().ok())
-        .any(|img| image_too_large(img));
-    let contains_gif = images
+        .any(|img| image_too_large(db, img));
+    let gifs = images
         .iter()
         .filter_map(|r| r.as_ref().ok())
-        .any(|img| img.is_gif());
-    if images.is_empty() || contains_error || contains_large_image && contains_gif {
-        send_the_old_way(bot, db.channel(), images, captions).await?;
-        return Ok(());
-    }
-    assert!(!images.is_empty());
-    if contains_large_image {
+        .filter(|img| img.is_gif())
+        .count();
+    let contains_gif = gifs > 0;
+    // animations can't be sent as part of an album
+    let gif_outside_album = contains_gif && db.gif_as_animation;
+    let mut sent = if images.is_empty()
+        || contains_error
+        || contains_large_image && contains_gif
+        || gif_outside_album
+        || images.len() < db.min_album_size
+    {
+        send_the_old_way(bot, db, images, captions, overlay.as_deref()).await?
+    } else if contains_large_image {
         assert!(!contains_gif);
-        // TODO: replace with:
-        // send_as_document_group(bot, target, images, captions).await?;
         if images.len() == 1 {
             let img: Image = images.into_iter().find_map(|x| x.ok()).unwrap();
             let caption = captions.remove(0);
-            let doc = Document::with_bytes(&img.name, &img.data).caption(caption);
+            let markup = Markup::new(&caption);
+            let doc = Document::with_bytes(&img.name, &img.data).caption(markup.text());
+            throttle::wait(db.channel(), 1).await;
             let first_msg = bot
                 .send_document(db.channel(), doc)
                 .is_notification_disabled(true)
                 .call()
                 .await?;
-            for caption in captions {
-                bot.send_message(db.channel(), caption)
+            journal::record(first_msg.id);
+            let mut ids = vec![first_msg.id];
+            for caption in &captions {
+                let markup = Markup::new(caption);
+                throttle::wait(db.channel(), 1).await;
+                let msg = bot
+                    .send_message(db.channel(), markup.text())
                     .is_web_page_preview_disabled(true)
                     .in_reply_to(first_msg.id)
                     .call()
                     .await?;
+                journal::record(msg.id);
+                ids.push(msg.id);
             }
+            captions.insert(0, caption);
+            Sent {
+                ids,
+                media_caption: true,
+                captions,
+                notes: Vec::new(),
+                gifs: 0,
+                failed_gifs: 0,
+                messages: Vec::new(),
+            }
+        } else if images.len() <= TG_ALBUM_LIMIT {
+            let images = images
+                .into_iter()
+                .map(|r| r.expect("error not filtered out, check the logic"))
+                .collect();
+            send_as_document_group(bot, db, images, captions).await?
         } else {
-            send_the_old_way(bot, db.channel(), images, captions).await?;
+            send_the_old_way(bot, db, images, captions, overlay.as_deref()).await?
         }
     } else {
-        let images: Vec<Image> = images
+        let mut images: Vec<Image> = images
             .into_iter()
             .map(|r| r.expect("error not filtered out, check the logic"))
             .collect();
+        if db.collage_thumbnails
+            && images.len() < TG_ALBUM_LIMIT
+            && preprocess::wants_collage(&images)
+        {
+            match preprocess::collage(&images) {
+                Ok(collage) => images.insert(0, collage),
+                Err(e) => error!("{}: failed to make collage: {}", pic.id, e),
+            }
+        }
+
+        send_as_photo_group(bot, db, images, captions, overlay.as_deref()).await?
+    };
+    // notes go back on in this order when captions are edited
+    sent.notes.splice(0..0, notes);
+    sent.gifs = gifs;
+    for strip in &strips {
+        throttle::wait(db.channel(), 1).await;
+        let msg = bot
+            .send_document(db.channel(), Document::with_bytes(&strip.name, &strip.data))
+            .is_notification_disabled(true)
+            .in_reply_to(sent.ids[0])
+            .call()
+            .await?;
+        journal::record(msg.id);
+    }
+    for url in &pic.videos {
+        throttle::wait(db.channel(), 1).await;
+        let video = match download_video(url).await {
+            Ok(data) => bot
+                .send_video(
+                    db.channel(),
+                    Video::with_bytes(&prepare_video(db, url, data)),
+                )
+                .is_notification_disabled(true)
+                .in_reply_to(sent.ids[0])
+                .call()
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        let msg = match video {
+            Ok(msg) => msg,
+            Err(e) => {
+                // the post is out already, link what can't be uploaded
+                error!("{}: {}", url, e);
+                throttle::wait(db.channel(), 1).await;
+                bot.send_message(db.channel(), url.as_str())
+                    .is_notification_disabled(true)
+                    .in_reply_to(sent.ids[0])
+                    .call()
+                    .await?
+            }
+        };
+        journal::record(msg.id);
+    }
+    Ok(sent)
+}
 
-        send_as_photo_group(bot, db.channel(), images, captions).await?;
+async fn download_video(url: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(http::download_video(url, TG_VIDEO_SIZE_LIMIT).await?.data)
+}
+
+/// An MP4 telegram plays, remuxed where possible. The download goes out as
+/// is if that fails, e.g. without ffmpeg.
+fn prepare_video(db: &database::Database, url: &str, data: Vec<u8>) -> Vec<u8> {
+    match video_to_mp4(data.clone(), &db.convert, None) {
+        Ok(mp4) => mp4.data,
+        Err(e) => {
+            info!("{}: {}, sending it as downloaded", url, e);
+            data
+        }
+    }
+}
+
+/// Re-renders the captions of a sent post and edits the messages that
+/// changed, e.g. once the comment assets it links to are uploaded
+async fn edit_captions(
+    bot: &tbot::Bot,
+    db: &database::Database,
+    pic: &spider::Pic,
+    sent: &mut Sent,
+) -> anyhow::Result<()> {
+    let mut captions = format_caption(db, pic, false);
+    for note in &sent.notes {
+        add_note(&mut captions, note);
+    }
+    if captions.len() != sent.ids.len() {
+        // can't move text between messages
+        warn!("{}: caption layout changed, not editing", pic.id);
+        return Ok(());
+    }
+    let target = db.channel();
+    for (i, ((id, old), new)) in sent
+        .ids
+        .iter()
+        .zip(&sent.captions)
+        .zip(&captions)
+        .enumerate()
+    {
+        if old == new {
+            continue;
+        }
+        let markup = Markup::new(new);
+        if i == 0 && sent.media_caption {
+            throttle::wait(target, 1).await;
+            bot.edit_message_caption(target, *id, markup.text())
+                .call()
+                .await?;
+        } else {
+            throttle::wait(target, 1).await;
+            bot.edit_message_text(target, *id, markup.text())
+                .is_web_page_preview_disabled(true)
+                .call()
+                .await?;
+        }
+    }
+    sent.captions = captions;
+    Ok(())
+}
+
+/// Keeps the `max` images with the most pixels in their original order,
+/// failed downloads count as empty
+fn keep_largest<E>(images: Vec<Result<Image, E>>, max: usize) -> Vec<Result<Image, E>> {
+    let area = |r: &Result<Image, E>| {
+        r.as_ref()
+            .map_or(0, |img| img.width as u64 * img.height as u64)
+    };
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(area(&images[i])));
+    let keep: HashSet<usize> = order.into_iter().take(max).collect();
+    images
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep.contains(i))
+        .map(|(_, r)| r)
+        .collect()
+}
+
+fn upscale_if_small(img: Image, options: &preprocess::Upscale) -> Image {
+    if !preprocess::wants_upscale(&img, options) {
+        return img;
+    }
+    match preprocess::upscale(&img, options) {
+        Ok(upscaled) => upscaled,
+        Err(e) => {
+            error!("{}: failed to upscale: {:#}", img.url, e);
+            img
+        }
+    }
+}
+
+/// Replaces long strips with their segments, the originals are moved into
+/// `strips`. Strips are left alone if the segments don't fit into one album.
+fn split_long_strips<E>(
+    images: Vec<Result<Image, E>>,
+    ratio: f32,
+    strips: &mut Vec<Image>,
+) -> Vec<Result<Image, E>> {
+    let is_strip =
+        |r: &Result<Image, E>| matches!(r, Ok(img) if preprocess::is_long_strip(img, ratio));
+    let strip_count = images.iter().filter(|r| is_strip(r)).count();
+    if strip_count == 0 {
+        return images;
+    }
+    let max_segments =
+        (TG_ALBUM_LIMIT - (images.len() - strip_count).min(TG_ALBUM_LIMIT)) / strip_count;
+    if max_segments < 2 {
+        return images;
+    }
+    let mut r = Vec::with_capacity(TG_ALBUM_LIMIT);
+    for img in images {
+        match img {
+            Ok(img) if preprocess::is_long_strip(&img, ratio) => {
+                match preprocess::split_long_strip(&img, max_segments) {
+                    Ok(segments) => {
+                        r.extend(segments.into_iter().map(Ok));
+                        strips.push(img);
+                    }
+                    Err(e) => {
+                        error!("{}: failed to split long strip: {}", img.name, e);
+                        r.push(Ok(img));
+                    }
+                }
+            }
+            img => r.push(img),
+        }
     }
-    Ok(())
+    r
 }
 
-#[allow(unused)]
+/// tbot can't send documents as a group, this goes through `bot_api`
 async fn send_as_document_group(
     bot: &tbot::Bot,
-    target: ChatId<'_>,
+    db: &database::Database,
     images: Vec<Image>,
-    caption: Text<'_>,
-) -> anyhow::Result<()> {
+    mut captions: Vec<String>,
+) -> anyhow::Result<Sent> {
     assert!(!images.is_empty());
-    let mut first = true;
-    let group: Vec<GroupMedia> = images
-        .iter()
-        .map(|img| {
-            if first {
-                first = false;
-                let doc = Document::with_bytes(&img.name, &img.data).caption(caption);
-                todo!("tbot doesn't support ducoment as group")
-            } else {
-                let doc = Document::with_bytes(&img.name, &img.data);
-                todo!("tbot doesn't support ducoment as group")
-            }
+    let caption = captions.remove(0);
+    let markup = Markup::new(&caption);
+    let media: Vec<_> = (0..images.len())
+        .map(|i| match i {
+            0 => serde_json::json!({
+                "type": "document",
+                "media": "attach://file0",
+                "caption": markup.as_str(),
+                "parse_mode": markup.parse_mode(),
+            }),
+            i => serde_json::json!({
+                "type": "document",
+                "media": format!("attach://file{}", i),
+            }),
         })
         .collect();
-    bot.send_media_group(target, &group)
-        .is_notification_disabled(true)
-        .call()
-        .await?;
-
-    Ok(())
+    let mut form = reqwest::multipart::Form::new()
+        .text("chat_id", db.channel_name().to_owned())
+        .text("media", serde_json::to_string(&media)?)
+        .text("disable_notification", "true");
+    for (i, img) in images.into_iter().enumerate() {
+        let part = reqwest::multipart::Part::bytes(img.data).file_name(img.name);
+        form = form.part(format!("file{}", i), part);
+    }
+    throttle::wait(db.channel(), media.len()).await;
+    let msgs = bot_api::call_raw_multipart(&db.token, "sendMediaGroup", form).await?;
+    let msg_ids: Vec<message::Id> = msgs
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|msg| msg["message_id"].as_u64())
+        .map(|id| message::Id(id as _))
+        .collect();
+    for id in &msg_ids {
+        journal::record(*id);
+    }
+    let first_msg_id = *msg_ids.first().context("tg return 0 msg")?;
+    let mut ids = vec![first_msg_id];
+    for caption in &captions {
+        let markup = Markup::new(caption);
+        throttle::wait(db.channel(), 1).await;
+        let msg = bot
+            .send_message(db.channel(), markup.text())
+            .is_web_page_preview_disabled(true)
+            .in_reply_to(first_msg_id)
+            .call()
+            .await?;
+        journal::record(msg.id);
+        ids.push(msg.id);
+    }
+    captions.insert(0, caption);
+    Ok(Sent {
+        ids,
+        media_caption: true,
+        captions,
+        notes: Vec::new(),
+        gifs: 0,
+        failed_gifs: 0,
+        messages: Vec::new(),
+    })
 }
 
 async fn send_as_photo_group(
     bot: &tbot::Bot,
-    target: ChatId<'_>,
+    db: &database::Database,
     images: Vec<Image>,
-    mut captions: Vec<Text<'_>>,
-) -> anyhow::Result<()> {
+    mut captions: Vec<String>,
+    overlay: Option<&str>,
+) -> anyhow::Result<Sent> {
     assert!(!images.is_empty());
+    let target = db.channel();
+    let mut truncated = false;
+    let mut failed_gifs = Vec::new();
     enum Or {
         Video(Vec<u8>),
-        Photo(Vec<u8>),
+        Photo { name: String, data: Vec<u8> },
     }
     let data: Vec<_> = images
         .into_iter()
         .map(|img| {
             if img.is_gif() {
-                video_to_mp4(img.data).map(Or::Video)
+                let poster_source = img.data.clone();
+                match video_to_mp4(img.data, &db.convert, overlay) {
+                    Ok(mp4) => {
+                        truncated |= mp4.truncated;
+                        Ok(Or::Video(mp4.data))
+                    }
+                    Err(e) => {
+                        error!("{}: {}", img.url, e);
+                        let data = convert::first_frame(&poster_source)?;
+                        failed_gifs.push(img.url);
+                        Ok(Or::Photo {
+                            name: format!("{}.jpg", img.name),
+                            data,
+                        })
+                    }
+                }
             } else {
-                Ok(Or::Photo(img.data))
+                Ok(Or::Photo {
+                    name: img.name,
+                    data: img.data,
+                })
             }
         })
-        .collect::<Result<_, _>>()?;
-    let caption = captions.remove(0);
+        .collect::<anyhow::Result<_>>()?;
+    let mut notes = Vec::new();
+    if truncated {
+        notes.push(TRUNCATED_NOTE.to_string());
+    }
+    notes.extend(failed_gifs.iter().map(|url| gif_failed_note(url)));
+    for note in &notes {
+        add_note(&mut captions, note);
+    }
+    let markups: Vec<Markup> = captions.iter().map(|s| Markup::new(s)).collect();
+    let mut texts = markups.iter().map(Markup::text);
+    let caption = texts.next().expect("at least one caption");
     let mut first = true;
     let group: Vec<GroupMedia> = data
         .iter()
@@ -254,184 +1605,635 @@ async fn send_as_photo_group(
                 first = false;
                 Video::with_bytes(v).caption(caption).into()
             }
-            (Or::Photo(p), true) => {
+            (Or::Photo { data, .. }, true) => {
                 first = false;
-                Photo::with_bytes(p).caption(caption).into()
+                Photo::with_bytes(data).caption(caption).into()
             }
             (Or::Video(v), false) => Video::with_bytes(v).into(),
-            (Or::Photo(p), false) => Photo::with_bytes(p).into(),
+            (Or::Photo { data, .. }, false) => Photo::with_bytes(data).into(),
         })
         .collect();
+    throttle::wait(target, group.len()).await;
     let first_msg = bot
         .send_media_group(target, &group)
         .is_notification_disabled(true)
         .call()
         .await?;
-    let first_msg_id = first_msg.get(0).expect("tg return 0 msg").id;
-    for caption in captions {
-        bot.send_message(target, caption)
+    for msg in &first_msg {
+        journal::record(msg.id);
+    }
+    let first_msg_id = first_msg.first().expect("tg return 0 msg").id;
+    let mut ids = vec![first_msg_id];
+    for caption in texts {
+        throttle::wait(target, 1).await;
+        let msg = bot
+            .send_message(target, caption)
             .is_web_page_preview_disabled(true)
             .in_reply_to(first_msg_id)
             .call()
             .await?;
+        journal::record(msg.id);
+        ids.push(msg.id);
+    }
+    if db.attach_originals {
+        // photos are recompressed by telegram, reply with the untouched bytes
+        for d in &data {
+            if let Or::Photo { name, data } = d {
+                throttle::wait(target, 1).await;
+                let msg = bot
+                    .send_document(target, Document::with_bytes(name, data))
+                    .is_notification_disabled(true)
+                    .in_reply_to(first_msg_id)
+                    .call()
+                    .await?;
+                journal::record(msg.id);
+            }
+        }
     }
 
-    Ok(())
+    Ok(Sent {
+        ids,
+        media_caption: true,
+        captions,
+        notes,
+        gifs: 0,
+        failed_gifs: failed_gifs.len(),
+        messages: Vec::new(),
+    })
+}
+
+/// What happened to a GIF on its way to telegram
+#[derive(Default)]
+struct Converted {
+    truncated: bool,
+    failed: bool,
 }
 
 async fn upload_single_image(
     bot: &tbot::Bot,
+    db: &database::Database,
     target: ChatId<'_>,
-    img: Image
-) -> anyhow::Result<tbot::types::Message> {
+    img: Image,
+    overlay: Option<&str>,
+) -> anyhow::Result<(tbot::types::Message, Converted)> {
+    let mut converted = Converted::default();
     let msg = if img.is_gif() {
-        let mp4 = video_to_mp4(img.data)?;
-        bot.send_video(target, Video::with_bytes(&mp4))
+        let poster_source = img.data.clone();
+        match send_gif(bot, db, target, img.data, overlay).await {
+            Ok((msg, t)) => {
+                converted.truncated = t;
+                msg
+            }
+            Err(e) => {
+                error!("{}: {}", img.url, e);
+                converted.failed = true;
+                let poster = convert::first_frame(&poster_source)?;
+                let note = gif_failed_note(&img.url);
+                let photo = Photo::with_bytes(&poster).caption(Text::with_markdown(&note));
+                throttle::wait(target, 1).await;
+                bot.send_photo(target, photo)
+                    .is_notification_disabled(true)
+                    .call()
+                    .await?
+            }
+        }
+    } else if image_too_large(db, &img) {
+        throttle::wait(target, 1).await;
+        bot.send_document(target, Document::with_bytes(&img.name, &img.data))
             .is_notification_disabled(true)
             .call()
             .await?
-    } else if image_too_large(&img) {
-        bot.send_document(target, Document::with_bytes(&img.name, &img.data))
+    } else {
+        throttle::wait(target, 1).await;
+        bot.send_photo(target, Photo::with_bytes(&img.data))
+            .is_notification_disabled(true)
+            .call()
+            .await?
+    };
+    Ok((msg, converted))
+}
+
+async fn send_gif(
+    bot: &tbot::Bot,
+    db: &database::Database,
+    target: ChatId<'_>,
+    data: Vec<u8>,
+    overlay: Option<&str>,
+) -> anyhow::Result<(tbot::types::Message, bool)> {
+    let original = (db.convert.keep_smaller_gif && overlay.is_none()).then(|| data.clone());
+    let converted = video_to_mp4(data, &db.convert, overlay);
+    let smaller = match (&converted, original) {
+        (Ok(mp4), Some(gif)) if gif.len() < mp4.data.len() => Some(gif),
+        // the GIF as it is beats no GIF at all
+        (Err(e), Some(gif)) => {
+            warn!("conversion failed, sending the GIF as it is: {}", e);
+            Some(gif)
+        }
+        _ => None,
+    };
+    if let Some(gif) = smaller {
+        // telegram plays GIFs sent with sendAnimation just fine
+        throttle::wait(target, 1).await;
+        let msg = bot
+            .send_animation(target, Animation::with_bytes(&gif))
+            .is_notification_disabled(true)
+            .call()
+            .await?;
+        return Ok((msg, false));
+    }
+    let mp4 = converted?;
+    let msg = if db.gif_as_animation {
+        throttle::wait(target, 1).await;
+        bot.send_animation(target, Animation::with_bytes(&mp4.data))
             .is_notification_disabled(true)
             .call()
             .await?
     } else {
-        bot.send_photo(target, Photo::with_bytes(&img.data))
+        throttle::wait(target, 1).await;
+        bot.send_video(target, Video::with_bytes(&mp4.data))
             .is_notification_disabled(true)
             .call()
             .await?
     };
-    Ok(msg)
+    Ok((msg, mp4.truncated))
 }
 
 async fn send_the_old_way(
     bot: &tbot::Bot,
-    target: ChatId<'_>,
+    db: &database::Database,
     images: Vec<Result<Image, (anyhow::Error, &'_ str)>>,
-    mut captions: Vec<Text<'_>>,
-) -> anyhow::Result<()> {
+    mut captions: Vec<String>,
+    overlay: Option<&str>,
+) -> anyhow::Result<Sent> {
+    let target = db.channel();
+    let mut truncated = false;
+    let mut failed_gifs = 0;
     for img_result in images {
         match img_result {
             Ok(img) => {
-                upload_single_image(bot, target, img).await?;
+                let (msg, converted) = upload_single_image(bot, db, target, img, overlay).await?;
+                journal::record(msg.id);
+                truncated |= converted.truncated;
+                failed_gifs += converted.failed as usize;
             }
             Err((e, img_url)) => {
                 error!("{}: {}", img_url, e);
-                bot.send_message(target, &*img_url)
+                throttle::wait(target, 1).await;
+                let msg = bot
+                    .send_message(target, img_url)
                     .is_notification_disabled(true)
                     .call()
                     .await?;
+                journal::record(msg.id);
             }
         }
 
         tokio::time::delay_for(Duration::from_secs(3)).await;
     }
-    let caption = captions.remove(0);
+    let mut notes = Vec::new();
+    if truncated {
+        notes.push(TRUNCATED_NOTE.to_string());
+        add_note(&mut captions, TRUNCATED_NOTE);
+    }
+    let markups: Vec<Markup> = captions.iter().map(|s| Markup::new(s)).collect();
+    let mut texts = markups.iter().map(Markup::text);
+    let caption = texts.next().expect("at least one caption");
+    throttle::wait(target, 1).await;
     let first_msg = bot
         .send_message(target, caption)
         .is_web_page_preview_disabled(true)
         .call()
         .await?;
-    for caption in captions {
-        bot.send_message(target, caption)
+    journal::record(first_msg.id);
+    let mut ids = vec![first_msg.id];
+    for caption in texts {
+        throttle::wait(target, 1).await;
+        let msg = bot
+            .send_message(target, caption)
             .is_web_page_preview_disabled(true)
             .in_reply_to(first_msg.id)
             .call()
             .await?;
+        journal::record(msg.id);
+        ids.push(msg.id);
     }
-    Ok(())
+    Ok(Sent {
+        ids,
+        media_caption: false,
+        captions,
+        notes,
+        gifs: 0,
+        failed_gifs,
+        messages: Vec::new(),
+    })
+}
+
+fn vote_overlay(db: &database::Database, pic: &spider::Pic) -> Option<String> {
+    db.convert
+        .overlay_votes
+        .then(|| format!("OO:{} XX:{}", pic.oo, pic.xx))
+}
+
+/// Adds a line to the first caption, or right after it if it doesn't fit
+fn add_note(captions: &mut Vec<String>, note: &str) {
+    if captions[0].chars().count() + 1 + note.chars().count() <= TG_CAPTION_LIMIT {
+        captions[0].push('\n');
+        captions[0].push_str(note);
+    } else {
+        captions.insert(1, note.into());
+    }
+}
+
+fn image_failed_note(url: &str) -> String {
+    format!("[{}]({})", IMAGE_FAILED_NOTE, url)
 }
 
-fn image_too_large(img: &Image) -> bool {
-    std::cmp::max(img.width, img.height) > TG_IMAGE_DIMENSION_LIMIT
-        && img.data.len() > LOW_QUALITY_IMG_SIZE
+/// The smaller size image hosts serve next to `large/`
+fn thumbnail_url(url: &str) -> Option<String> {
+    url.contains("/large/")
+        .then(|| url.replacen("/large/", "/mw600/", 1))
+}
+
+fn is_forbidden(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| status == reqwest::StatusCode::FORBIDDEN)
+}
+
+async fn placeholder(url: &str) -> anyhow::Result<Image> {
+    let thumbnail = thumbnail_url(url).context("no thumbnail")?;
+    preprocess::placeholder(&download_image(&thumbnail).await?)
+}
+
+fn gif_failed_note(url: &str) -> String {
+    // a `)` would end the link early
+    format!("[{}]({})", GIF_FAILED_NOTE, url.replace(')', "%29"))
+}
+
+/// Whether the image has to be sent as a document, telegram would crush it
+/// as a photo
+fn image_too_large(db: &database::Database, img: &Image) -> bool {
+    let (long, short) = if img.width > img.height {
+        (img.width, img.height)
+    } else {
+        (img.height, img.width)
+    };
+    let too_narrow = db
+        .max_photo_ratio
+        .is_some_and(|ratio| long as f32 > short as f32 * ratio);
+    long > TG_IMAGE_DIMENSION_LIMIT && img.data.len() > LOW_QUALITY_IMG_SIZE
         || img.data.len() > TG_IMAGE_SIZE_LIMIT
+        || too_narrow
 }
 
-fn format_caption(db: &database::Database, pic: &spider::Pic) -> Vec<String> {
-    let mut msg = format!(
-        "*{}*: https://jandan.net/t/{}\n",
-        pic.author.replace("*", ""),
-        pic.id,
-    );
-    if !pic.text.is_empty() {
-        msg.push_str(&telegram_md_escape(&pic.text));
+/// Fills `{author}`, `{id}`, `{link}`, `{oo}` and `{xx}` in a caption
+/// header/footer, the template itself is markdown.
+fn render_template(template: &str, pic: &spider::Pic) -> String {
+    template
+        .replace(
+            "{author}",
+            &telegram_md_escape(&sanitize_author(&pic.author)),
+        )
+        .replace("{id}", &pic.id)
+        .replace("{link}", &format!("https://jandan.net/t/{}", pic.id))
+        .replace("{oo}", &pic.oo.to_string())
+        .replace("{xx}", &pic.xx.to_string())
+        .replace(
+            "{via}",
+            &telegram_md_escape(pic.via.as_deref().unwrap_or_default()),
+        )
+}
+
+/// `("YYYY-MM-DD", "HH:MM")` of `unix_time` in jandan's time (UTC+8)
+fn jandan_time(unix_time: u64) -> (String, String) {
+    let t = unix_time + 8 * 60 * 60;
+    let (days, secs) = ((t / (24 * 60 * 60)) as i64, t % (24 * 60 * 60));
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (
+        format!("{:04}-{:02}-{:02}", y, m, d),
+        format!("{:02}:{:02}", secs / 3600, secs % 3600 / 60),
+    )
+}
+
+/// With `pending`, comment images that aren't uploaded yet are rendered as
+/// "［图片加载中］" links to the source, to be replaced by `edit_captions`.
+fn format_caption(db: &database::Database, pic: &spider::Pic, pending: bool) -> Vec<String> {
+    let footer = db.caption_footer.as_ref().map(|t| render_template(t, pic));
+    let footer_len = footer.as_ref().map_or(0, |s| s.chars().count() + 1);
+    let mut msg = db
+        .caption_header
+        .as_ref()
+        .map(|t| render_template(t, pic) + "\n")
+        .unwrap_or_default();
+    let author = sanitize_author(&pic.author);
+    match &pic.author_link {
+        // markdown entities can't be nested, a linked name isn't bold
+        Some(url) => write!(msg, "[{}]({})", author, url),
+        None => write!(msg, "*{}*", author),
+    }
+    .unwrap();
+    writeln!(msg, ": https://jandan.net/t/{}", pic.id).unwrap();
+    if let (Some(format), Some(t)) = (&db.published_format, pic.published_at) {
+        let (date, time) = jandan_time(t);
+        writeln!(
+            msg,
+            "{}",
+            format.replace("{date}", &date).replace("{time}", &time)
+        )
+        .unwrap();
+    }
+    // posts often repeat their text in the alt texts, or a line twice
+    let mut seen = HashSet::new();
+    let mut unseen = |line: &str| {
+        let normalized = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        normalized.is_empty() || seen.insert(normalized)
+    };
+    let text = pic
+        .text
+        .lines()
+        .filter(|line| unseen(line))
+        .map(telegram_md_escape)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let quoted = is_long(db, &text);
+    let mut tail = String::new();
+    for alt in pic.alt_texts.iter().filter(|alt| unseen(alt)) {
+        writeln!(tail, "［{}］", telegram_md_escape(alt)).unwrap();
+    }
+    if db.max_images.is_some_and(|max| pic.images.len() > max) {
+        writeln!(
+            tail,
+            "[查看全部 {} 张](https://jandan.net/t/{})",
+            pic.images.len(),
+            pic.id
+        )
+        .unwrap();
+    }
+    if let Some(via) = &pic.via {
+        writeln!(tail, "来源: {}", telegram_md_escape(via)).unwrap();
+    }
+    write!(tail, "*OO*: {} *XX*: {}", pic.oo, pic.xx).unwrap();
+    let more = format!("\n[更多吐槽…](https://jandan.net/t/{})", pic.id);
+    let more_len = more.chars().count();
+    let max_msgs = if db.single_caption {
+        1
+    } else {
+        db.max_extra_captions.map_or(usize::MAX, |n| n + 1)
+    };
+    // the text gives way to the footer, and in a single caption to the link
+    // to the comments. A quote, the line break and "…" take 4 more.
+    let more_room = if max_msgs == 1 && !pic.comments.hot.is_empty() {
+        more_len
+    } else {
+        0
+    };
+    let room = TG_CAPTION_LIMIT
+        .saturating_sub(msg.chars().count() + tail.chars().count() + footer_len + more_room + 4);
+    let text = if text.chars().count() > room {
+        let mut cut = split_message(&text, room.max(1)).swap_remove(0);
+        cut.truncate(cut.trim_end().len());
+        cut.push('…');
+        cut
+    } else {
+        text
+    };
+    if quoted {
+        writeln!(msg, "{}{}{}", QUOTE_START, text, QUOTE_END).unwrap();
+    } else if !text.is_empty() {
+        msg.push_str(&text);
         msg.push('\n');
     }
-    write!(msg, "*OO*: {} *XX*: {}", pic.oo, pic.xx).unwrap();
+    msg.push_str(&tail);
     let mut msgs = vec![msg];
+    let mut mentioned = HashSet::new();
+    let mut dropped = false;
     for comment in &pic.comments.hot {
-        let msg = msgs.last_mut().expect("never");
+        let before = mentioned.clone();
+        let context = match reply_context(&pic.comments, comment).filter(|_| db.reply_context) {
+            // the mention itself is then "(见上)"
+            Some((id, line)) if mentioned.insert(id) => line + "\n",
+            _ => String::new(),
+        };
+        let content =
+            comment_to_tg_md(db, &comment.content, &pic.comments, &mut mentioned, pending);
+        let content = if is_long(db, &content) {
+            // quotes have to start a line
+            let sep = if context.is_empty() { "\n" } else { "" };
+            format!("{}{}{}{}", sep, QUOTE_START, content, QUOTE_END)
+        } else {
+            content
+        };
         let formatted = format!(
-            "\n*{}*: {}\n*OO*: {}, *XX*: {}",
-            &comment.author.replace("*", ""),
-            comment_to_tg_md(db, &comment.content),
+            "\n*{}*: {}{}\n*OO*: {}, *XX*: {}",
+            sanitize_author(&comment.author),
+            context,
+            content,
             comment.oo,
             comment.xx
         );
-        if msg.chars().count() + formatted.chars().count() > TG_CAPTION_LIMIT {
+        // the footer goes to the end of the first message
+        let reserved = if msgs.len() == 1 { footer_len } else { 0 };
+        // the last message allowed keeps room for the link to the rest
+        let is_last = msgs.len() >= max_msgs;
+        let formatted_len = formatted.chars().count();
+        let msg = msgs.last_mut().expect("never");
+        let room = if is_last { more_len } else { 0 };
+        if msg.chars().count() + formatted_len + reserved + room <= TG_CAPTION_LIMIT {
+            msg.push_str(&formatted);
+        } else if !is_last
+            && (msgs.len() + 1 < max_msgs || formatted_len + more_len <= TG_CAPTION_LIMIT)
+        {
             msgs.push(formatted);
         } else {
-            msg.push_str(&formatted);
+            // whole comments only, so no markdown entity is cut in half. A
+            // later, shorter comment may still fit.
+            mentioned = before;
+            dropped = true;
         }
     }
+    if dropped {
+        msgs.last_mut().expect("never").push_str(&more);
+    }
+    if let Some(footer) = footer {
+        msgs[0].push('\n');
+        msgs[0].push_str(&footer);
+    }
     msgs
 }
 
-fn comment_to_tg_md(db: &database::Database, comment: &spider::RichText) -> String {
+/// Whether `text` is collapsed into an expandable quote
+fn is_long(db: &database::Database, text: &str) -> bool {
+    db.expandable_quote_over
+        .is_some_and(|limit| text.chars().count() > limit)
+}
+
+/// `mentioned` collects the mentions rendered so far, a comment that was
+/// already quoted in the same caption is rendered as "name (见上)".
+/// `comments` are looked up for `quote_mentions`.
+fn comment_to_tg_md(
+    db: &database::Database,
+    comment: &spider::RichText,
+    comments: &spider::Comments,
+    mentioned: &mut HashSet<u64>,
+    pending: bool,
+) -> String {
     let mut r = String::new();
     for e in comment.entities() {
         use spider::TextEntity::*;
         match e {
             Text(s) => r.push_str(&telegram_md_escape(s)),
+            Bold(s) => r.push_str(&md_entity('*', s)),
+            Italic(s) => r.push_str(&md_entity('_', s)),
+            Link { text, url } => {
+                write!(r, "[{}]({})", strip_md(text), url.replace(')', "%29")).expect("never fail")
+            }
             Br => r.push('\n'),
             Img(url) => {
                 if let Some(tg_link) = db.get_img(url) {
                     write!(r, "[［图片］]({})", tg_link).expect("never fail");
+                } else if pending {
+                    write!(r, "[［图片加载中］]({})", url).expect("never fail");
                 } else {
                     r.push_str(&telegram_md_escape(url))
                 }
             }
             Mention { name, id } => {
-                if let Some(msg_link) = db.get_comment(id) {
+                if !mentioned.insert(id) {
+                    write!(r, "{} (见上)", telegram_md_escape(name)).expect("never fail");
+                    continue;
+                } else if let Some(msg_link) = db.get_comment(id) {
                     write!(r, "[{}]({})", name, msg_link).expect("never fail");
                 } else {
                     r.push_str(&telegram_md_escape(name))
                 }
+                if let Some(quoted) = comments.get(id).filter(|_| db.quote_mentions) {
+                    write!(r, "「{}」", telegram_md_escape(&quote(quoted))).expect("never fail");
+                }
             }
         }
     }
     r.trim().to_string() // TODO: zero alloc?
 }
 
+/// "回复 @name: …" with the start of the comment that `comment` replies to,
+/// if it opens with a mention of one of `comments`
+fn reply_context(comments: &spider::Comments, comment: &spider::Comment) -> Option<(u64, String)> {
+    use spider::TextEntity::*;
+    let (name, id) = match comment
+        .content
+        .entities()
+        .find(|e| !matches!(e, Br) && !matches!(e, Text(s) if s.trim().is_empty()))?
+    {
+        Mention { name, id } => (name, id),
+        _ => return None,
+    };
+    let parent = comments.get(id)?;
+    let line = format!(
+        "回复 {}: {}",
+        telegram_md_escape(name),
+        telegram_md_escape(&quote(parent))
+    );
+    Some((id, line))
+}
+
+fn drop_blocked_comments(blocklist: &database::Blocklist, comments: &mut spider::Comments) {
+    let blocked = |c: &spider::Comment| {
+        blocklist.blocks_author(&c.author) || blocklist.blocks_text(&plain_text(&c.content))
+    };
+    comments.hot.retain(|c| !blocked(c));
+    comments.mentioned.retain(|c| !blocked(c));
+}
+
+/// The start of a comment as plain text
+fn quote(comment: &spider::Comment) -> String {
+    truncate(&plain_text(&comment.content), QUOTE_LIMIT)
+}
+
+/// Rich text on one line, without markup
+fn plain_text(content: &spider::RichText) -> String {
+    let mut text = String::new();
+    for e in content.entities() {
+        use spider::TextEntity::*;
+        match e {
+            Text(s) | Bold(s) | Italic(s) | Link { text: s, .. } => text.push_str(s),
+            Br => text.push(' '),
+            Img(_) => text.push_str("［图片］"),
+            Mention { name, .. } => text.push_str(name),
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 async fn upload_comment_images(
     bot: &tbot::Bot,
     db: &mut database::Database,
     c: &spider::Comments,
 ) -> Result<(), anyhow::Error> {
+    let mut urls = Vec::new();
+    let mut seen = HashSet::new();
     for comment in c.hot.iter().chain(c.mentioned.iter()) {
         for entry in comment.content.entities() {
             if let spider::TextEntity::Img(url) = entry {
-                if db.get_img(url).is_some() {
-                    continue;
+                if db.get_img(url).is_none() && seen.insert(url) {
+                    urls.push(url.to_owned());
                 }
-                match download_image(url).await {
-                    Ok(img) => {
-                        let msg = upload_single_image(bot, db.assets_channel(), img).await?;
-                        db.put_img(url.to_string(), msg.id.0.into()).await;
-                    }
-                    Err(e) => {
-                        error!("{}: {}", url, e);
-                        bot.send_message(db.assets_channel(), url)
-                            .is_notification_disabled(true)
-                            .call()
-                            .await?;
+            }
+        }
+    }
+
+    // downloads run ahead while the uploads go out one by one through the
+    // flood limiter, dropping `rx` on error stops the downloads
+    let (tx, mut rx) = futures::channel::mpsc::channel(COMMENT_DOWNLOAD_CONCURRENCY);
+    let downloads = stream::iter(urls)
+        .map(|url| async move {
+            let img = download_image(&url).await;
+            Ok((url, img))
+        })
+        .buffered(COMMENT_DOWNLOAD_CONCURRENCY)
+        .forward(tx);
+    let uploads = async move {
+        while let Some((url, img)) = rx.next().await {
+            match img {
+                Ok(img) => {
+                    let final_url = img.url.clone();
+                    let hash = content_hash(&img.data);
+                    // mirrors serve the same image under other URLs
+                    let asset = match db.get_img_by_hash(&hash) {
+                        Some(asset) => asset,
+                        None => {
+                            let (msg, _) =
+                                upload_single_image(bot, db, db.assets_channel(), img, None)
+                                    .await?;
+                            db.put_img_hash(hash, msg.id.0.into())
+                        }
+                    };
+                    if final_url != url {
+                        db.put_img(final_url, asset.clone());
                     }
+                    db.put_img(url, asset);
+                }
+                Err(e) => {
+                    error!("{}: {}", url, e);
+                    throttle::wait(db.assets_channel(), 1).await;
+                    bot.send_message(db.assets_channel(), url.as_str())
+                        .is_notification_disabled(true)
+                        .call()
+                        .await?;
                 }
             }
         }
-    }
-    Ok(())
+        Ok::<_, anyhow::Error>(())
+    };
+    future::join(downloads, uploads).await.1
 }
 
 async fn upload_comment_mentions(
@@ -445,19 +2247,459 @@ async fn upload_comment_mentions(
         }
         let text = format!(
             "*{}*: {}\n*OO*: {}, *XX*: {}",
-            &comment.author.replace("*", ""),
-            comment_to_tg_md(db, &comment.content),
+            sanitize_author(&comment.author),
+            comment_to_tg_md(db, &comment.content, c, &mut HashSet::new(), false),
             comment.oo,
             comment.xx
         );
-        let text = Text::with_markdown(&text);
+        let mut parts = split_message(&text, TG_MESSAGE_LIMIT - CONTINUED_NOTE.chars().count() - 1);
 
+        throttle::wait(db.mentions_channel(), 1).await;
         let msg = bot
-            .send_message(db.assets_channel(), text)
+            .send_message(db.mentions_channel(), Text::with_markdown(&parts.remove(0)))
             .is_notification_disabled(true)
             .call()
             .await?;
-        db.put_comment(comment.id, msg.id.0.into()).await;
+        // links point to the start of the comment
+        db.put_comment(comment.id, msg.id.0.into());
+        for part in parts {
+            let part = format!("{}\n{}", CONTINUED_NOTE, part);
+            throttle::wait(db.mentions_channel(), 1).await;
+            bot.send_message(db.mentions_channel(), Text::with_markdown(&part))
+                .is_notification_disabled(true)
+                .in_reply_to(msg.id)
+                .call()
+                .await?;
+        }
     }
     Ok(())
 }
+
+/// Cuts `text`, legacy Markdown, into pieces of at most `limit` characters,
+/// at line breaks where possible. Entities are only cut if they don't fit
+/// in a piece of their own, they are closed and opened again at the cut.
+/// Never returns an empty list.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut r = vec![String::new()];
+    let mut len = 0;
+    // where the last line of the piece starts, and its length
+    let mut line: Option<(usize, usize)> = None;
+    for unit in md_units(text, limit) {
+        let unit_len = unit.chars().count();
+        if len + unit_len > limit && len > 0 {
+            let last = r.last_mut().expect("never");
+            let (tail, tail_len) = match line {
+                Some((start, line_len)) if line_len + unit_len <= limit => {
+                    (last.split_off(start), line_len)
+                }
+                _ => (String::new(), 0),
+            };
+            r.push(tail);
+            len = tail_len;
+            line = None;
+        }
+        let last = r.last_mut().expect("never");
+        last.push_str(&unit);
+        len += unit_len;
+        if unit.ends_with('\n') {
+            line = Some((last.len(), 0));
+        } else if let Some((_, line_len)) = &mut line {
+            *line_len += unit_len;
+        }
+    }
+    r
+}
+
+/// The parts of legacy Markdown that `split_message` doesn't cut: entities,
+/// escaped characters and graphemes. Entities over `limit` come in pieces
+/// that are entities again.
+fn md_units(text: &str, limit: usize) -> Vec<Cow<'_, str>> {
+    let mut r = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let entity = match c {
+            '\\' => rest
+                .char_indices()
+                .nth(2)
+                .map(|(i, _)| i)
+                .or(Some(rest.len())),
+            '*' | '_' | '`' => rest[1..].find(c).map(|end| end + 2),
+            '[' => rest
+                .find("](")
+                .and_then(|mid| rest[mid..].find(')').map(|end| mid + end + 1)),
+            _ => None,
+        };
+        let unit = match entity {
+            Some(end) => &rest[..end],
+            None => rest.graphemes(true).next().expect("never"),
+        };
+        rest = &rest[unit.len()..];
+        if unit.chars().count() <= limit || c == '\\' || entity.is_none() {
+            r.push(Cow::Borrowed(unit));
+            continue;
+        }
+        let (open, inner, close) = match c {
+            '[' => {
+                let mid = unit.find("](").expect("never");
+                (&unit[..1], &unit[1..mid], &unit[mid..])
+            }
+            _ => (
+                &unit[..1],
+                &unit[1..unit.len() - 1],
+                &unit[unit.len() - 1..],
+            ),
+        };
+        let room = limit.saturating_sub(open.chars().count() + close.chars().count());
+        if room == 0 {
+            // not even one character fits, formatting is lost
+            r.extend(unit.graphemes(true).map(Cow::Borrowed));
+            continue;
+        }
+        let mut piece = String::new();
+        let mut piece_len = 0;
+        for g in inner.graphemes(true) {
+            let g_len = g.chars().count();
+            if piece_len + g_len > room && piece_len > 0 {
+                r.push(Cow::Owned(format!("{}{}{}", open, piece, close)));
+                piece.clear();
+                piece_len = 0;
+            }
+            piece.push_str(g);
+            piece_len += g_len;
+        }
+        r.push(Cow::Owned(format!("{}{}{}", open, piece, close)));
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn db() -> database::Database {
+        serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "token": "",
+            "channel": "@channel",
+            "assets_channel": "@assets",
+            "imgs": {},
+            "comments": { "123": { "msg": 1 } },
+        }))
+        .unwrap()
+    }
+
+    fn pic() -> spider::Pic {
+        spider::Pic {
+            section: spider::SECTION.into(),
+            author: "a_b".into(),
+            author_link: None,
+            link: String::new(),
+            id: "42".into(),
+            oo: 1,
+            xx: 2,
+            published_at: None,
+            text: String::new(),
+            via: None,
+            images: Vec::new(),
+            videos: Vec::new(),
+            alt_texts: Vec::new(),
+            comments: spider::Comments {
+                hot: Vec::new(),
+                mentioned: Vec::new(),
+            },
+            parts: Vec::new(),
+        }
+    }
+
+    fn comment(id: u64, html: &str) -> spider::Comment {
+        spider::Comment {
+            id,
+            author: "name".into(),
+            oo: 0,
+            xx: 0,
+            content: spider::parse_comment(html.to_string()),
+            mentions: Vec::new(),
+            date: None,
+        }
+    }
+
+    #[test]
+    fn censored_posts() {
+        let mut pic = pic();
+        pic.text = " ［夹］\n".into();
+        assert!(pic.is_censored());
+        pic.images = vec!["https://wx1.sinaimg.cn/large/a.jpg".into()];
+        assert!(!pic.is_censored());
+        pic.images.clear();
+        pic.text = "夹子".into();
+        assert!(!pic.is_censored());
+    }
+
+    #[test]
+    fn jandan_times() {
+        assert_eq!(jandan_time(0), ("1970-01-01".into(), "08:00".into()));
+        // 2024-02-29 23:59 UTC
+        assert_eq!(
+            jandan_time(1709251140),
+            ("2024-03-01".into(), "07:59".into())
+        );
+    }
+
+    #[test]
+    fn thumbnail_urls() {
+        assert_eq!(
+            thumbnail_url("https://wx1.sinaimg.cn/large/abc.jpg").as_deref(),
+            Some("https://wx1.sinaimg.cn/mw600/abc.jpg")
+        );
+        assert_eq!(thumbnail_url("https://jandan.net/abc.jpg"), None);
+    }
+
+    #[test]
+    fn gif_failed_notes() {
+        assert_eq!(
+            gif_failed_note("https://x.y/a_(1).gif"),
+            format!("[{}](https://x.y/a_(1%29.gif)", GIF_FAILED_NOTE)
+        );
+    }
+
+    #[test]
+    fn content_names() {
+        assert_eq!(
+            content_name(b"", "abcd.jpg"),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855.jpg"
+        );
+        assert_eq!(
+            content_name(b"", "abcd"),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn caption_template() {
+        let pic = pic();
+        assert_eq!(
+            render_template("via {author} {link} {oo}/{xx}", &pic),
+            "via a b https://jandan.net/t/42 1/2"
+        );
+    }
+
+    #[test]
+    fn author_link() {
+        let db = db();
+        let mut pic = pic();
+        assert!(format_caption(&db, &pic, false)[0].starts_with("*a b*: "));
+        pic.author_link = Some("https://jandan.net/member/1".into());
+        assert!(format_caption(&db, &pic, false)[0]
+            .starts_with("[a b](https://jandan.net/member/1): https://jandan.net/t/42\n"));
+    }
+
+    #[test]
+    fn dedup_caption_lines() {
+        let mut pic = pic();
+        pic.text = "same  line\nother\nsame line".into();
+        pic.alt_texts = vec!["other".into(), "alt".into()];
+        let caption = format_caption(&db(), &pic, false).remove(0);
+        assert_eq!(
+            caption,
+            "*a b*: https://jandan.net/t/42\nsame  line\nother\n［alt］\n*OO*: 1 *XX*: 2"
+        );
+    }
+
+    #[test]
+    fn author_name() {
+        assert_eq!(sanitize_author(" *a_b*\n  [c] "), "a b c");
+        let long = "蛋".repeat(AUTHOR_NAME_LIMIT + 1);
+        assert_eq!(sanitize_author(&long), "蛋".repeat(AUTHOR_NAME_LIMIT) + "…");
+        let flags = "🇨🇳".repeat(AUTHOR_NAME_LIMIT + 1);
+        assert_eq!(
+            sanitize_author(&flags),
+            "🇨🇳".repeat(AUTHOR_NAME_LIMIT) + "…"
+        );
+    }
+
+    #[test]
+    fn mentions_channel() {
+        let mut db = db();
+        db.mentions_channel = Some("@mentions".into());
+        let comment = spider::parse_comment(
+            r##"<a href="#tucao-123" data-id="123" class="tucao-link">@name</a>"##.to_string(),
+        );
+        assert_eq!(
+            comment_to_tg_md(&db, &comment, &pic().comments, &mut HashSet::new(), false),
+            "[@name](https://t.me/mentions/1)"
+        );
+    }
+
+    #[test]
+    fn quote_mentions() {
+        let mut db = db();
+        db.quote_mentions = true;
+        let comments = spider::Comments {
+            hot: Vec::new(),
+            mentioned: vec![comment(123, &"a_b<br>".repeat(30))],
+        };
+        let comment = spider::parse_comment(
+            r##"<a href="#tucao-123" data-id="123" class="tucao-link">@name</a>"##.to_string(),
+        );
+        let quoted = "a\\_b ".repeat(20) + "…";
+        let mut mentioned = HashSet::new();
+        assert_eq!(
+            comment_to_tg_md(&db, &comment, &comments, &mut mentioned, false),
+            format!("[@name](https://t.me/assets/1)「{}」", quoted)
+        );
+        assert_eq!(
+            comment_to_tg_md(&db, &comment, &comments, &mut mentioned, false),
+            "@name (见上)"
+        );
+    }
+
+    #[test]
+    fn expandable_quotes() {
+        let mut db = db();
+        let mut pic = pic();
+        pic.text = "0123456789".into();
+        assert!(!format_caption(&db, &pic, false)[0].contains(QUOTE_START));
+        db.expandable_quote_over = Some(5);
+        let caption = format_caption(&db, &pic, false).remove(0);
+        assert!(caption.contains(&format!("\n{}0123456789{}\n", QUOTE_START, QUOTE_END)));
+        assert!(Markup::new(&caption)
+            .as_str()
+            .contains("\n**>0123456789||\n"));
+    }
+
+    #[test]
+    fn reply_contexts() {
+        let mut db = db();
+        db.reply_context = true;
+        let mut pic = pic();
+        pic.comments.hot = vec![comment(
+            2,
+            r##"<a href="#tucao-1" data-id="1" class="tucao-link">@name</a> me too"##,
+        )];
+        pic.comments.mentioned = vec![comment(1, "first<br>line")];
+        let caption = format_caption(&db, &pic, false).concat();
+        assert!(caption.contains("*name*: 回复 @name: first line\n@name (见上) me too"));
+
+        db.reply_context = false;
+        let caption = format_caption(&db, &pic, false).concat();
+        assert!(!caption.contains("回复"));
+    }
+
+    #[test]
+    fn single_caption() {
+        let mut db = db();
+        db.single_caption = true;
+        let mut pic = pic();
+        pic.comments.hot = vec![
+            comment(1, &"a".repeat(600)),
+            comment(2, &"b".repeat(600)),
+            comment(3, "short"),
+        ];
+        let msgs = format_caption(&db, &pic, false);
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].chars().count() <= TG_CAPTION_LIMIT);
+        assert!(msgs[0].contains(&"a".repeat(600)));
+        assert!(!msgs[0].contains(&"b".repeat(600)));
+        assert!(msgs[0].contains("short"));
+        assert!(msgs[0].ends_with("[更多吐槽…](https://jandan.net/t/42)"));
+    }
+
+    #[test]
+    fn footer_always_fits() {
+        let mut db = db();
+        db.single_caption = true;
+        db.caption_footer = Some("via 煎蛋无聊图".into());
+        let mut pic = pic();
+        pic.text = "t".repeat(2000);
+        pic.comments.hot = vec![comment(1, "short")];
+        let msgs = format_caption(&db, &pic, false);
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].chars().count() <= TG_CAPTION_LIMIT);
+        assert!(msgs[0].contains("t…"));
+        assert!(msgs[0].ends_with("\nvia 煎蛋无聊图"));
+        assert!(msgs[0].contains("[更多吐槽…](https://jandan.net/t/42)"));
+    }
+
+    #[test]
+    fn max_extra_captions() {
+        let mut db = db();
+        db.max_extra_captions = Some(1);
+        let mut pic = pic();
+        pic.comments.hot = (1..=4).map(|id| comment(id, &"c".repeat(900))).collect();
+        let msgs = format_caption(&db, &pic, false);
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[1].ends_with("[更多吐槽…](https://jandan.net/t/42)"));
+        assert!(msgs.iter().all(|m| m.chars().count() <= TG_CAPTION_LIMIT));
+    }
+
+    #[test]
+    fn split_messages() {
+        assert_eq!(split_message("", 4), vec![""]);
+        assert_eq!(split_message("ab\ncd\nef", 6), vec!["ab\ncd\n", "ef"]);
+        assert_eq!(split_message("abcdefg\nh", 3), vec!["abc", "def", "g\nh"]);
+        assert_eq!(split_message("🇨🇳🇨🇳", 3), vec!["🇨🇳", "🇨🇳"]);
+        assert_eq!(split_message("a\\*b", 2), vec!["a", "\\*", "b"]);
+    }
+
+    #[test]
+    fn split_entities() {
+        // the cut would fall inside the link
+        assert_eq!(
+            split_message("aaaa [link](https://x.y/z) bbb", 25),
+            vec!["aaaa ", "[link](https://x.y/z) bbb"]
+        );
+        assert_eq!(
+            split_message("*bold\nline* c", 12),
+            vec!["*bold\nline* ", "c"]
+        );
+        assert_eq!(split_message("x *abcdef*", 5), vec!["x ", "*abc*", "*def*"]);
+        assert_eq!(
+            split_message("[abcdef](u)", 7),
+            vec!["[ab](u)", "[cd](u)", "[ef](u)"]
+        );
+    }
+
+    /// Comments that broke formatting before, every `fixtures/comments/*.html`
+    /// must render to the `.md` next to it
+    #[test]
+    fn comment_fixtures() {
+        let db = db();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/comments");
+        let mut checked = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let html = entry.unwrap().path();
+            if html.extension().is_none_or(|ext| ext != "html") {
+                continue;
+            }
+            let input = fs::read_to_string(&html).unwrap();
+            let expected = fs::read_to_string(html.with_extension("md")).unwrap();
+            let comment = spider::parse_comment(input.trim_end_matches('\n').to_owned());
+            assert_eq!(
+                comment_to_tg_md(&db, &comment, &pic().comments, &mut HashSet::new(), false),
+                expected.trim_end_matches('\n'),
+                "{}",
+                html.display()
+            );
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn dedup_mentions() {
+        let db = db();
+        let comment = spider::parse_comment(
+            r##"<a href="#tucao-123" data-id="123" class="tucao-link">@name</a> COMMENT"##
+                .to_string(),
+        );
+        let mut mentioned = HashSet::new();
+        assert_eq!(
+            comment_to_tg_md(&db, &comment, &pic().comments, &mut mentioned, false),
+            "[@name](https://t.me/assets/1) COMMENT"
+        );
+        assert_eq!(
+            comment_to_tg_md(&db, &comment, &pic().comments, &mut mentioned, false),
+            "@name (见上) COMMENT"
+        );
+    }
+}
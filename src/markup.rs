@@ -0,0 +1,140 @@
+use tbot::types::parameters::Text;
+
+/// Around text that should be collapsed, at the start of a line. Captions
+/// are written in telegram's legacy Markdown, which has no quotes, so
+/// captions with these marks are sent as MarkdownV2 instead.
+pub const QUOTE_START: char = '\u{E000}';
+pub const QUOTE_END: char = '\u{E001}';
+
+/// A caption the way it is sent
+pub struct Markup {
+    text: String,
+    v2: bool,
+}
+
+impl Markup {
+    pub fn new(md: &str) -> Self {
+        if md.contains(QUOTE_START) {
+            Markup {
+                text: to_v2(md),
+                v2: true,
+            }
+        } else {
+            Markup {
+                text: md.to_owned(),
+                v2: false,
+            }
+        }
+    }
+
+    pub fn text(&self) -> Text<'_> {
+        if self.v2 {
+            Text::with_markdown_v2(&self.text)
+        } else {
+            Text::with_markdown(&self.text)
+        }
+    }
+
+    /// For raw Bot API calls
+    pub fn parse_mode(&self) -> &'static str {
+        if self.v2 {
+            "MarkdownV2"
+        } else {
+            "Markdown"
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Rewrites the legacy Markdown the captions are built with, turning the
+/// quote marks into expandable blockquotes
+fn to_v2(md: &str) -> String {
+    const SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+    let mut r = String::with_capacity(md.len() * 2);
+    let mut chars = md.chars().peekable();
+    let (mut in_code, mut in_link_text, mut in_quote) = (false, false, false);
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => {
+                in_code = !in_code;
+                r.push(c);
+            }
+            '\\' if in_code => {
+                r.push('\\');
+                r.push(c);
+            }
+            _ if in_code => r.push(c),
+            '\\' if chars.peek().is_some_and(|n| "[*_`".contains(*n)) => {
+                r.push('\\');
+                r.push(chars.next().expect("peeked"));
+            }
+            '*' | '_' => r.push(c),
+            '[' if !in_link_text => {
+                in_link_text = true;
+                r.push(c);
+            }
+            ']' if in_link_text && chars.peek() == Some(&'(') => {
+                in_link_text = false;
+                r.push_str("](");
+                chars.next();
+                for c in chars.by_ref() {
+                    if c == ')' {
+                        break;
+                    }
+                    if c == '\\' {
+                        r.push('\\');
+                    }
+                    r.push(c);
+                }
+                r.push(')');
+            }
+            QUOTE_START => {
+                in_quote = true;
+                r.push_str("**>");
+            }
+            QUOTE_END => {
+                in_quote = false;
+                r.push_str("||");
+            }
+            '\n' if in_quote => r.push_str("\n>"),
+            c => {
+                if SPECIAL.contains(c) {
+                    r.push('\\');
+                }
+                r.push(c);
+            }
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn v2() {
+        assert_eq!(
+            to_v2("*a\\_b*: [［图片］](https://t.me/a_b/1)\n1.5 \\* 2!"),
+            "*a\\_b*: [［图片］](https://t.me/a_b/1)\n1\\.5 \\* 2\\!"
+        );
+        assert_eq!(
+            to_v2(&format!(
+                "*name*:\n{}line (1)\nline 2{}\n*OO*",
+                QUOTE_START, QUOTE_END
+            )),
+            "*name*:\n**>line \\(1\\)\n>line 2||\n*OO*"
+        );
+    }
+
+    #[test]
+    fn legacy_untouched() {
+        let md = "*name*: 1.5 [x](https://jandan.net/t/1)";
+        let markup = Markup::new(md);
+        assert_eq!(markup.as_str(), md);
+        assert_eq!(markup.parse_mode(), "Markdown");
+    }
+}
@@ -0,0 +1,56 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+const PROBE: &str = ".jandan_pic_bot.probe";
+
+/// Fails if any of `dirs` can't be written to, or has less than
+/// `min_free_mb` MiB available
+pub fn check<P: AsRef<Path>>(dirs: &[P], min_free_mb: Option<u64>) -> anyhow::Result<()> {
+    for dir in dirs {
+        let dir = dir.as_ref();
+        let probe = dir.join(PROBE);
+        fs::write(&probe, b"")
+            .and_then(|_| fs::remove_file(&probe))
+            .with_context(|| format!("{} is not writable", dir.display()))?;
+        if let Some(min) = min_free_mb {
+            let free = free_space(dir)? / 1024 / 1024;
+            if free < min {
+                bail!(
+                    "only {} MiB free in {}, need {} MiB",
+                    free,
+                    dir.display(),
+                    min
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users
+fn free_space(dir: &Path) -> anyhow::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("statvfs {}", dir.display()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writable() {
+        let tmp = std::env::temp_dir();
+        assert!(check(&[&tmp], Some(0)).is_ok());
+        assert!(check(&[tmp.join("does/not/exist")], None).is_err());
+        assert!(check(&[&tmp], Some(u64::MAX)).is_err());
+    }
+}
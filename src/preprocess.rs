@@ -0,0 +1,208 @@
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::Image;
+
+/// A segment of a long strip is at most this many times taller than wide.
+const SEGMENT_RATIO: u32 = 2;
+const JPEG_QUALITY: u8 = 90;
+/// Images whose longest side is below this are thumbnails.
+const THUMBNAIL_DIMENSION: u32 = 400;
+/// Don't bother making a collage for fewer thumbnails.
+const COLLAGE_MIN_COUNT: usize = 4;
+const COLLAGE_BACKGROUND: image::Rgb<u8> = image::Rgb([255, 255, 255]);
+/// Nearest-neighbor upscaling stops at this factor.
+const MAX_UPSCALE_FACTOR: u32 = 4;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Upscale {
+    /// Images whose longest side is below this are upscaled
+    pub below: u32,
+    /// External upscaler like Real-ESRGAN, `{input}` and `{output}` are
+    /// replaced by PNG paths. Nearest-neighbor by an integer factor if unset.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+}
+
+pub fn is_long_strip(img: &Image, ratio: f32) -> bool {
+    !img.is_gif() && img.width > 0 && img.height as f32 / img.width as f32 > ratio
+}
+
+pub fn is_thumbnail(img: &Image) -> bool {
+    !img.is_gif() && std::cmp::max(img.width, img.height) < THUMBNAIL_DIMENSION
+}
+
+pub fn wants_collage(images: &[Image]) -> bool {
+    images.len() >= COLLAGE_MIN_COUNT && images.iter().all(is_thumbnail)
+}
+
+pub fn wants_upscale(img: &Image, options: &Upscale) -> bool {
+    let longest = std::cmp::max(img.width, img.height);
+    !img.is_gif() && longest > 0 && longest < options.below
+}
+
+pub fn upscale(img: &Image, options: &Upscale) -> Result<Image> {
+    let decoded = image::load_from_memory_with_format(&img.data, img.format)?;
+    let scaled = match &options.command {
+        Some(command) => upscale_with(command, &decoded)?,
+        None => {
+            let longest = std::cmp::max(img.width, img.height);
+            let factor = options.below.div_ceil(longest).min(MAX_UPSCALE_FACTOR);
+            decoded.resize(img.width * factor, img.height * factor, FilterType::Nearest)
+        }
+    };
+    let (width, height) = scaled.dimensions();
+    let mut data = Vec::new();
+    scaled.write_to(&mut data, ImageOutputFormat::Png)?;
+    let stem = img
+        .name
+        .rsplit_once('.')
+        .map_or(&*img.name, |(stem, _)| stem);
+    Ok(Image {
+        format: image::ImageFormat::Png,
+        name: format!("{}.png", stem),
+        width,
+        height,
+        data,
+        url: img.url.clone(),
+    })
+}
+
+fn upscale_with(command: &[String], img: &DynamicImage) -> Result<DynamicImage> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => bail!("empty upscale command"),
+    };
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("jandan-upscale-{}-in.png", std::process::id()));
+    let output = dir.join(format!("jandan-upscale-{}-out.png", std::process::id()));
+    img.save_with_format(&input, image::ImageFormat::Png)?;
+    let status = Command::new(program)
+        .args(args.iter().map(|arg| {
+            arg.replace("{input}", &input.to_string_lossy())
+                .replace("{output}", &output.to_string_lossy())
+        }))
+        .status();
+    let result = match status {
+        Ok(status) if status.success() => image::open(&output).map_err(Into::into),
+        Ok(status) => Err(anyhow::anyhow!("{} exited with {}", program, status)),
+        Err(e) => Err(e.into()),
+    };
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+    result
+}
+
+pub fn screenshot(command: &[String], url: &str) -> Result<Image> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => bail!("empty screenshot command"),
+    };
+    let output = std::env::temp_dir().join(format!("jandan-screenshot-{}.png", std::process::id()));
+    let status = Command::new(program)
+        .args(args.iter().map(|arg| {
+            arg.replace("{url}", url)
+                .replace("{output}", &output.to_string_lossy())
+        }))
+        .status();
+    let result = match status {
+        Ok(status) if status.success() => std::fs::read(&output).map_err(Into::into),
+        Ok(status) => Err(anyhow::anyhow!("{} exited with {}", program, status)),
+        Err(e) => Err(e.into()),
+    };
+    let _ = std::fs::remove_file(&output);
+    let data = result?;
+    let (width, height) =
+        image::load_from_memory_with_format(&data, image::ImageFormat::Png)?.dimensions();
+    Ok(Image {
+        format: image::ImageFormat::Png,
+        name: "screenshot.png".into(),
+        width,
+        height,
+        data,
+        url: url.to_owned(),
+    })
+}
+
+/// Composites the images into a single grid, each centered in its cell.
+pub fn collage(images: &[Image]) -> Result<Image> {
+    let columns = (images.len() as f64).sqrt().ceil() as u32;
+    let rows = (images.len() as u32).div_ceil(columns);
+    let cell_width = images.iter().map(|img| img.width).max().unwrap_or(0);
+    let cell_height = images.iter().map(|img| img.height).max().unwrap_or(0);
+    let (width, height) = (cell_width * columns, cell_height * rows);
+
+    let mut canvas = image::RgbImage::from_pixel(width, height, COLLAGE_BACKGROUND);
+    for (i, img) in images.iter().enumerate() {
+        let decoded = image::load_from_memory_with_format(&img.data, img.format)?.to_rgb8();
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = column * cell_width + (cell_width - decoded.width()) / 2;
+        let y = row * cell_height + (cell_height - decoded.height()) / 2;
+        image::imageops::overlay(&mut canvas, &decoded, x, y);
+    }
+
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(canvas).write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    Ok(Image {
+        format: image::ImageFormat::Jpeg,
+        name: "collage.jpg".into(),
+        width,
+        height,
+        data,
+        url: String::new(),
+    })
+}
+
+/// A picture the size of `thumbnail` filled with its average color
+pub fn placeholder(thumbnail: &Image) -> Result<Image> {
+    let decoded = image::load_from_memory_with_format(&thumbnail.data, thumbnail.format)?;
+    let color = *decoded
+        .resize_exact(1, 1, FilterType::Triangle)
+        .to_rgb8()
+        .get_pixel(0, 0);
+    let canvas = image::RgbImage::from_pixel(thumbnail.width, thumbnail.height, color);
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(canvas).write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    Ok(Image {
+        format: image::ImageFormat::Jpeg,
+        name: "placeholder.jpg".into(),
+        width: thumbnail.width,
+        height: thumbnail.height,
+        data,
+        url: thumbnail.url.clone(),
+    })
+}
+
+/// Slices a long vertical strip into at most `max_segments` JPEG segments.
+pub fn split_long_strip(img: &Image, max_segments: usize) -> Result<Vec<Image>> {
+    let decoded = image::load_from_memory_with_format(&img.data, img.format)?;
+    let (width, height) = decoded.dimensions();
+    let count = height
+        .div_ceil(width * SEGMENT_RATIO)
+        .clamp(1, max_segments as u32);
+    let segment_height = height.div_ceil(count);
+    let stem = img
+        .name
+        .rsplit_once('.')
+        .map_or(&*img.name, |(stem, _)| stem);
+    (0..count)
+        .map(|i| {
+            let y = i * segment_height;
+            let h = segment_height.min(height - y);
+            let segment = DynamicImage::ImageRgb8(decoded.crop_imm(0, y, width, h).to_rgb8());
+            let mut data = Vec::new();
+            segment.write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+            Ok(Image {
+                format: image::ImageFormat::Jpeg,
+                name: format!("{}_{}.jpg", stem, i + 1),
+                width,
+                height: h,
+                data,
+                url: img.url.clone(),
+            })
+        })
+        .collect()
+}
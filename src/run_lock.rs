@@ -0,0 +1,110 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::database::now;
+
+/// Lets several instances share one database, e.g. a standby on another
+/// host with the database on shared storage. Whoever creates `path` first
+/// runs, the others exit. A lock older than `stale_after` seconds belonged
+/// to an instance that died and is taken over.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Config {
+    pub path: String,
+    pub stale_after: u64,
+}
+
+/// Removes the lock file when dropped
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("failed to release {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// `None` if another instance holds the lock
+pub fn acquire(config: &Config) -> anyhow::Result<Option<RunLock>> {
+    let path = PathBuf::from(&config.path);
+    for _ in 0..2 {
+        // create_new is atomic, also on NFS since v3
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => {
+                writeln!(f, "{}\n{}", now(), holder())
+                    .with_context(|| format!("write {}", path.display()))?;
+                return Ok(Some(RunLock { path }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e).with_context(|| format!("create {}", path.display())),
+        }
+        let s = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            // released in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let mut lines = s.lines();
+        // a half written file has no time yet, it's newly taken
+        let taken_at = lines
+            .next()
+            .and_then(|l| l.parse().ok())
+            .unwrap_or_else(now);
+        if now().saturating_sub(taken_at) <= config.stale_after {
+            return Ok(None);
+        }
+        warn!(
+            "taking over the lock of {}, held since {}",
+            lines.next().unwrap_or("unknown"),
+            taken_at
+        );
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+        }
+    }
+    // someone else took over the stale lock first
+    Ok(None)
+}
+
+/// `pid@hostname`, for the logs of whoever takes over
+fn holder() -> String {
+    let mut buf = [0u8; 256];
+    let host = if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    } else {
+        "unknown".to_owned()
+    };
+    format!("{}@{}", std::process::id(), host)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        let path = std::env::temp_dir().join(format!("jandan_pic_bot.{}.lock", holder()));
+        let config = Config {
+            path: path.to_string_lossy().into_owned(),
+            stale_after: 60,
+        };
+        let lock = acquire(&config).unwrap();
+        assert!(lock.is_some());
+        assert!(acquire(&config).unwrap().is_none());
+        drop(lock);
+        assert!(!path.exists());
+
+        fs::write(&path, "0\n1@elsewhere\n").unwrap();
+        assert!(acquire(&config).unwrap().is_some());
+    }
+}
@@ -1,33 +1,92 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::ops::Range;
-use std::time::Duration;
 
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
 use lazy_static::lazy_static;
+use log::warn;
 use marksman_escape::Unescape;
 use regex::Regex;
 use reqwest::header;
-use scraper::Html;
-use serde::Deserialize;
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+
+use crate::http::{self, CLIENT};
 
 const JANDAN_HOME: &str = "http://jandan.net/";
-const TUCAO_API: &str = "http://jandan.net/tucao/";
-
-thread_local! {
-    pub static CLIENT: reqwest::Client = {
-        let headers = header::HeaderMap::new();
-        const USER_AGENT: &str = concat!(
-            env!("CARGO_PKG_NAME"),
-            "/",
-            env!("CARGO_PKG_VERSION"),
-            " (+https://t.me/jandan_pic)"
-        );
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .user_agent(header::HeaderValue::from_static(USER_AGENT))
-            .default_headers(headers)
-            .build()
-            .unwrap()
+/// Section scraped when none are configured, also the one of history
+/// entries from before sections
+pub const SECTION: &str = "pic";
+const TUCAO_API: &str = "tucao/";
+/// Pages of tucao read at most while looking for mentioned comments
+const TUCAO_MAX_PAGES: usize = 20;
+/// Seconds between two parts of a set at most, post ages are only exact
+/// to the hour
+const MULTIPART_WINDOW: u64 = 60 * 60;
+
+/// Where jandan is reached, e.g. a mirror, or an IP with `host` set for
+/// when its DNS is poisoned
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Endpoint {
+    /// Takes the place of `http://jandan.net/`
+    pub base: String,
+    /// Sent as the Host header
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            base: JANDAN_HOME.to_owned(),
+            host: None,
+        }
+    }
+}
+
+impl Endpoint {
+    async fn get(&self, path: &str) -> Result<reqwest::Response, SpiderError> {
+        let url = format!("{}/{}", self.base.trim_end_matches('/'), path);
+        let url = reqwest::Url::parse(&url)
+            .map_err(|e| SpiderError::Parse(format!("endpoint URL {}: {}", url, e)))?;
+        let req = CLIENT.with(|client| client.get(url.clone()));
+        let req = match &self.host {
+            Some(host) => req.header(header::HOST, host.as_str()),
+            None => req,
+        };
+        http::send(req, &url).await.map_err(SpiderError::Http)
+    }
+}
+
+/// Why scraping failed, telling jandan being unreachable apart from jandan
+/// having changed
+#[derive(Debug, thiserror::Error)]
+pub enum SpiderError {
+    /// The request didn't go through or was answered with an error status,
+    /// including hosts skipped by the circuit breaker
+    #[error("request to jandan failed")]
+    Http(#[source] anyhow::Error),
+    /// A response that doesn't parse, e.g. the tucao JSON or a vote count
+    #[error("unexpected response from jandan: {0}")]
+    Parse(String),
+    /// Something the scraper looks for is gone from the page, mostly the
+    /// position in this file that looked for it
+    #[error("jandan changed its pages: {0}")]
+    Layout(Cow<'static, str>),
+    /// The post was deleted, its page or tucao are not found
+    #[error("the post was deleted")]
+    Gone,
+}
+
+impl From<reqwest::Error> for SpiderError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            SpiderError::Parse(e.to_string())
+        } else {
+            SpiderError::Http(e.into())
+        }
     }
 }
 
@@ -39,27 +98,73 @@ pub struct Comment {
     pub xx: u32,
     pub content: RichText,
     pub mentions: Vec<u64>,
+    /// Unix timestamp, if the API sent one
+    pub date: Option<u64>,
+}
+
+/// How hot comments are ordered in captions
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommentOrder {
+    /// As returned by jandan
+    #[default]
+    Api,
+    /// OO minus XX
+    Votes,
+    /// OO minus XX, decaying with age like Hacker News' ranking. Comments
+    /// without a date are treated as brand new.
+    VotesByAge,
+}
+
+impl CommentOrder {
+    pub fn sort(self, comments: &mut [Comment], now: u64) {
+        let votes = |c: &Comment| c.oo as f64 - c.xx as f64;
+        match self {
+            CommentOrder::Api => (),
+            CommentOrder::Votes => comments.sort_by(|a, b| votes(b).total_cmp(&votes(a))),
+            CommentOrder::VotesByAge => {
+                let score = |c: &Comment| {
+                    let hours = c.date.map_or(0, |d| now.saturating_sub(d)) as f64 / 3600.0;
+                    votes(c) / (hours + 2.0).powf(1.5)
+                };
+                comments.sort_by(|a, b| score(b).total_cmp(&score(a)))
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Pic {
+    /// The front page list the post is from, e.g. "pic" or "ooxx"
+    pub section: String,
     pub author: String,
+    /// The author's profile page, if the post links to one
+    pub author_link: Option<String>,
     pub link: String,
     pub id: String,
     pub oo: u32,
     pub xx: u32,
+    /// Unix time, jandan only tells how long ago so it's as exact as that
+    pub published_at: Option<u64>,
     pub text: String,
+    /// Credit to where the post is from, taken out of a "via ..." line of
+    /// the text
+    pub via: Option<String>,
     pub images: Vec<String>,
+    /// Sources of embedded `<video>`s
+    pub videos: Vec<String>,
+    /// Non-empty `alt`/`title` of the images
+    pub alt_texts: Vec<String>,
     pub comments: Comments,
+    /// IDs of the other posts merged into this one
+    pub parts: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct TucaoResp {
     code: i32,
     hot_tucao: Vec<Tucao>,
-    #[allow(unused)]
     tucao: Vec<Tucao>,
-    #[allow(unused)]
     has_next_page: bool,
 }
 
@@ -72,6 +177,8 @@ struct Tucao {
     comment_content: String,
     vote_positive: u32,
     vote_negative: u32,
+    #[serde(default)]
+    comment_date_int: Option<u64>,
 }
 
 fn deserialize_comment_with_unescape<'de, D>(deserializer: D) -> Result<String, D::Error>
@@ -83,49 +190,47 @@ where
     struct BorrowCow<'a>(#[serde(borrow)] Cow<'a, str>);
     let s = BorrowCow::deserialize(deserializer)?.0;
     String::from_utf8(Unescape::new(s.bytes()).collect::<Vec<u8>>())
-        .map_err(|e| serde::de::Error::custom(e))
+        .map_err(serde::de::Error::custom)
 }
 
 #[derive(Debug, Clone, PartialEq)]
 enum EntityRange {
-    Text {
-        range: Range<usize>,
-    },
-    Img {
-        range: Range<usize>,
+    Text(Range<usize>),
+    Bold(Range<usize>),
+    Italic(Range<usize>),
+    Link {
+        text: Range<usize>,
         url: Range<usize>,
     },
+    Img(Range<usize>),
     Mention {
-        range: Range<usize>,
         name: Range<usize>,
         id: u64,
     },
-    Br {
-        range: Range<usize>,
-    },
+    Br,
 }
 impl EntityRange {
-    fn range(&self) -> Range<usize> {
-        use EntityRange::*;
-        match self {
-            Text { range } | Img { range, .. } | Mention { range, .. } | Br { range } => {
-                range.clone()
-            }
-        }
-    }
-    fn to_text_entity<'a>(&'a self, s: &'a str) -> Option<TextEntity<'a>> {
+    fn to_text_entity<'a>(&'a self, s: &'a str) -> TextEntity<'a> {
         use EntityRange::*;
         match self {
-            Text { range, .. } => s.get(range.clone()).map(TextEntity::Text),
-            Img { url, .. } => s.get(url.clone()).map(TextEntity::Img),
-            Mention { name, id, .. } => s
-                .get(name.clone())
-                .map(|name| TextEntity::Mention { name, id: *id }),
-            Br { range } => s.get(range.clone()).map(|_| TextEntity::Br),
+            Text(range) => TextEntity::Text(&s[range.clone()]),
+            Bold(range) => TextEntity::Bold(&s[range.clone()]),
+            Italic(range) => TextEntity::Italic(&s[range.clone()]),
+            Link { text, url } => TextEntity::Link {
+                text: &s[text.clone()],
+                url: &s[url.clone()],
+            },
+            Img(url) => TextEntity::Img(&s[url.clone()]),
+            Mention { name, id } => TextEntity::Mention {
+                name: &s[name.clone()],
+                id: *id,
+            },
+            Br => TextEntity::Br,
         }
     }
 }
 
+/// The text of a comment, `entities` point into `s`
 #[derive(Debug, Clone, PartialEq)]
 pub struct RichText {
     s: String,
@@ -135,107 +240,133 @@ impl RichText {
     pub fn entities<'a>(&'a self) -> impl Iterator<Item = TextEntity<'a>> {
         self.entities
             .iter()
-            .map(|range| range.to_text_entity(&self.s).expect(""))
+            .map(|range| range.to_text_entity(&self.s))
+    }
+
+    fn push_str(&mut self, s: &str) -> Range<usize> {
+        let start = self.s.len();
+        self.s.push_str(s);
+        start..self.s.len()
     }
+
+    /// Adjacent text of the same style ends up in one entity
+    fn push_text(&mut self, text: &str, style: Style) {
+        if text.is_empty() {
+            return;
+        }
+        let end = self.s.len();
+        let last = match (self.entities.last_mut(), style) {
+            (Some(EntityRange::Text(r)), Style::Plain)
+            | (Some(EntityRange::Bold(r)), Style::Bold)
+            | (Some(EntityRange::Italic(r)), Style::Italic)
+                if r.end == end =>
+            {
+                Some(r)
+            }
+            _ => None,
+        };
+        if let Some(r) = last {
+            self.s.push_str(text);
+            r.end = self.s.len();
+            return;
+        }
+        let range = self.push_str(text);
+        self.entities.push(match style {
+            Style::Plain => EntityRange::Text(range),
+            Style::Bold => EntityRange::Bold(range),
+            Style::Italic => EntityRange::Italic(range),
+        });
+    }
+
+    fn walk(&mut self, element: ElementRef, style: Style) {
+        for child in element.children() {
+            if let Some(text) = child.value().as_text() {
+                self.push_text(text, style);
+                continue;
+            }
+            let element = match ElementRef::wrap(child) {
+                Some(element) => element,
+                None => continue,
+            };
+            let value = element.value();
+            match value.name() {
+                "br" => self.entities.push(EntityRange::Br),
+                "img" => {
+                    if let Some(src) = value.attr("src") {
+                        let url = self.push_str(src);
+                        self.entities.push(EntityRange::Img(url));
+                    }
+                }
+                "a" => {
+                    let text = element.text().collect::<String>();
+                    let id = value.attr("data-id").and_then(|id| id.parse().ok());
+                    let href = value
+                        .attr("href")
+                        .map(fix_scheme)
+                        .filter(|href| href.starts_with("http://") || href.starts_with("https://"));
+                    if let Some(id) = id {
+                        let name = self.push_str(&text);
+                        self.entities.push(EntityRange::Mention { name, id });
+                    } else if let (Some(href), false) = (href, text.trim().is_empty()) {
+                        let text = self.push_str(&text);
+                        let url = self.push_str(&href);
+                        self.entities.push(EntityRange::Link { text, url });
+                    } else {
+                        // e.g. a link around an image
+                        self.walk(element, style);
+                    }
+                }
+                // bold wins over italic, telegram can't nest them
+                "b" | "strong" => self.walk(element, Style::Bold),
+                "i" | "em" if style == Style::Plain => self.walk(element, Style::Italic),
+                _ => self.walk(element, style),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Plain,
+    Bold,
+    Italic,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum TextEntity<'a> {
     Text(&'a str),
+    Bold(&'a str),
+    Italic(&'a str),
+    Link { text: &'a str, url: &'a str },
     Img(&'a str),
     Mention { name: &'a str, id: u64 },
     Br,
 }
 
-fn parse_comment(s: String) -> RichText {
-    lazy_static! {
-        static ref RULES: [(Regex, fn(m: regex::Captures) -> EntityRange); 3] = [
-            (
-                Regex::new(r#"<img src="(?P<img>[^"]+)" />"#).unwrap(),
-                |c| -> EntityRange {
-                    EntityRange::Img {
-                        range: c.get(0).unwrap().range(),
-                        url: c.name("img").expect("missing 'img' in regex").range(),
-                    }
-                }
-            ),
-            (
-                Regex::new(r#"<a .*data-id="(?P<id>\d+)".*>(?P<at>[^<]*)</a>"#).unwrap(),
-                |c| {
-                    EntityRange::Mention {
-                        range: c.get(0).unwrap().range(),
-                        name: c.name("at").expect("missing 'at' in regex").range(),
-                        id: c
-                            .name("id")
-                            .expect("missing 'id' in regex")
-                            .as_str()
-                            .parse()
-                            .expect("data-id format changed"),
-                    }
-                }
-            ),
-            (Regex::new("<br>").unwrap(), |c| {
-                EntityRange::Br {
-                    range: c.get(0).unwrap().range(),
-                }
-            })
-        ];
-    }
-
-    let mut entities: Vec<EntityRange> = RULES
-        .iter()
-        .map(|(reg, f)| reg.captures_iter(&s).map(f))
-        .flatten()
-        .collect();
-    entities.sort_by_key(|e| e.range().start);
-    let len_freezed: i128 = entities.len().try_into().expect("overflow");
-    for i in -1..len_freezed {
-        let start = if i == -1 {
-            0 // start of the string
-        } else {
-            entities[i as usize].range().end
-        };
-        let end = if i + 1 < len_freezed {
-            entities[(i + 1) as usize].range().start
-        } else {
-            s.len() // end of the string
-        };
-        assert!(start <= end, "overlap");
-        if start == end {
-            continue;
-        }
-        entities.push(EntityRange::Text {
-            range: Range { start, end },
-        })
-    }
-    entities.sort_by_key(|e| e.range().start);
-    RichText { s, entities }
+/// Tucao content is a HTML fragment, tags that don't mean anything to
+/// telegram are dropped and keep their text
+pub fn parse_comment(s: String) -> RichText {
+    let fragment = Html::parse_fragment(&s);
+    let mut r = RichText {
+        s: String::with_capacity(s.len()),
+        entities: Vec::new(),
+    };
+    r.walk(fragment.root_element(), Style::Plain);
+    r
 }
 
-fn fix_scheme(s: &str) -> Cow<str> {
+fn fix_scheme(s: &str) -> Cow<'_, str> {
     if s.starts_with("//") {
         let mut ns = String::with_capacity(6 + s.len());
         ns.push_str("https:");
-        ns.push_str(&s);
+        ns.push_str(s);
         Cow::Owned(ns)
     } else {
         Cow::Borrowed(s)
     }
 }
 
-fn extract_mentions(comment: &str) -> Vec<u64> {
-    lazy_static! {
-        // <a href="#tucao-12116426" data-id="12116426" class="tucao-link">
-        static ref MENTIONS: Regex = Regex::new(r#"<a .*data-id="(?P<id>\d+)".*>"#).unwrap();
-    }
-    MENTIONS
-        .captures_iter(comment)
-        .map(|c| c.name("id").expect("bug in regex").as_str())
-        .map(|id| id.parse::<u64>().expect("tucao ID format changed"))
-        .collect()
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Comments {
     pub hot: Vec<Comment>,
     pub mentioned: Vec<Comment>,
@@ -252,43 +383,89 @@ impl Comments {
 
 impl From<Tucao> for Comment {
     fn from(tucao: Tucao) -> Self {
-        let mentions = extract_mentions(&tucao.comment_content);
+        let content = parse_comment(tucao.comment_content);
+        let mentions = content
+            .entities()
+            .filter_map(|e| match e {
+                TextEntity::Mention { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect();
         Comment {
             id: tucao.comment_id,
             author: tucao.comment_author,
             oo: tucao.vote_positive,
             xx: tucao.vote_negative,
-            content: parse_comment(tucao.comment_content),
+            content,
             mentions,
+            date: tucao.comment_date_int,
         }
     }
 }
 
-async fn get_comments(id: &str) -> anyhow::Result<Comments> {
-    let url = format!("{}{}", TUCAO_API, id);
+async fn get_tucao(endpoint: &Endpoint, path: &str) -> Result<TucaoResp, SpiderError> {
+    let resp = endpoint.get(path).await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(SpiderError::Gone);
+    }
+    let resp = resp.error_for_status()?.json::<TucaoResp>().await?;
+    if resp.code != 0 {
+        return Err(SpiderError::Parse(format!(
+            "{} answered with code {}",
+            path, resp.code
+        )));
+    }
+    Ok(resp)
+}
 
-    let resp = CLIENT
-        .with(|client| client.get(&url))
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<TucaoResp>()
-        .await?;
-    assert_eq!(resp.code, 0);
+/// Whether a comment mentioned by `hot`, directly or through other
+/// mentioned comments, is not in `tucao`
+fn missing_mention(hot: &[Comment], tucao: &HashMap<u64, Comment>) -> bool {
+    let mut stack: Vec<u64> = hot
+        .iter()
+        .flat_map(|c| c.mentions.iter().copied())
+        .collect();
+    let mut visited = HashSet::new();
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        match tucao.get(&id) {
+            Some(c) => stack.extend_from_slice(&c.mentions),
+            None => return true,
+        }
+    }
+    false
+}
+
+async fn get_comments(endpoint: &Endpoint, id: &str) -> Result<Comments, SpiderError> {
+    let mut resp = get_tucao(endpoint, &format!("{}{}", TUCAO_API, id)).await?;
+    let hot: Vec<Comment> = resp.hot_tucao.drain(..).map(|c| c.into()).collect();
 
-    let mut tucao: HashMap<u64, Tucao> =
-        HashMap::from_iter(resp.tucao.into_iter().map(|c| (c.comment_id, c)));
+    // the first page only has the newest tucao, older pages continue after
+    // the last comment seen
+    let mut tucao: HashMap<u64, Comment> = HashMap::new();
+    for page in 1.. {
+        let last = resp.tucao.last().map(|c| c.comment_id);
+        let has_next_page = resp.has_next_page;
+        tucao.extend(resp.tucao.into_iter().map(|c| (c.comment_id, c.into())));
+        let last = match last {
+            Some(last) if has_next_page && page < TUCAO_MAX_PAGES => last,
+            _ => break,
+        };
+        if !missing_mention(&hot, &tucao) {
+            break;
+        }
+        resp = get_tucao(endpoint, &format!("{}{}/n/{}", TUCAO_API, id, last)).await?;
+    }
 
-    let hot: Vec<Comment> = resp.hot_tucao.into_iter().map(|c| c.into()).collect();
     let mut mentioned = Vec::new();
     let mut mentioned_id_stack: Vec<_> = hot
         .iter()
-        .map(|c| c.mentions.iter().cloned())
-        .flatten()
+        .flat_map(|c| c.mentions.iter().cloned())
         .collect();
     while let Some(id) = mentioned_id_stack.pop() {
-        if let Some(t) = tucao.remove(&id) {
-            let c: Comment = t.into();
+        if let Some(c) = tucao.remove(&id) {
             mentioned_id_stack.extend_from_slice(&c.mentions);
             mentioned.push(c);
         }
@@ -297,6 +474,129 @@ async fn get_comments(id: &str) -> anyhow::Result<Comments> {
     Ok(Comments { hot, mentioned })
 }
 
+impl Pic {
+    /// Censored posts stay in the list with their content replaced by "夹"
+    pub fn is_censored(&self) -> bool {
+        self.images.is_empty()
+            && self.videos.is_empty()
+            && self.text.trim().trim_matches(|c| "[]［］【】".contains(c)) == "夹"
+    }
+}
+
+/// The text without its first "via ..."/"来源：..." line, and what that
+/// line credits
+fn split_via(text: &str) -> (String, Option<String>) {
+    lazy_static! {
+        static ref VIA: Regex = Regex::new(
+            r"(?i)^\s*(?:(?:via|来源|出处|转自)(?:\s*[:：]\s*|\s+)|source\s*[:：]\s*)(\S.*?)\s*$"
+        )
+        .unwrap();
+    }
+    let mut via = None;
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        match VIA.captures(line).filter(|_| via.is_none()) {
+            Some(c) => via = Some(c[1].to_owned()),
+            None => lines.push(line),
+        }
+    }
+    match via {
+        Some(via) => (lines.join("\n"), Some(via)),
+        None => (text.to_owned(), None),
+    }
+}
+
+/// Seconds since a post went up, from the "@3 hours ago" next to its author
+fn post_age(text: &str) -> Option<u64> {
+    lazy_static! {
+        static ref AGE: Regex =
+            Regex::new(r"@\s*(\d+)\s*(sec|min|hour|day|week|month|year|秒|分钟|小时|天|周|月|年)")
+                .unwrap();
+    }
+    let c = AGE.captures(text)?;
+    let n: u64 = c[1].parse().ok()?;
+    let unit = match &c[2] {
+        "sec" | "秒" => 1,
+        "min" | "分钟" => 60,
+        "hour" | "小时" => 60 * 60,
+        "day" | "天" => 24 * 60 * 60,
+        "week" | "周" => 7 * 24 * 60 * 60,
+        "month" | "月" => 30 * 24 * 60 * 60,
+        _ => 365 * 24 * 60 * 60,
+    };
+    n.checked_mul(unit)
+}
+
+/// Returns `(n, total)` if the text is marked like "1/3"
+fn part_number(text: &str) -> Option<(u32, u32)> {
+    lazy_static! {
+        static ref PART: Regex = Regex::new(r"(?:^|\D)(\d{1,2}) ?/ ?(\d{1,2})(?:\D|$)").unwrap();
+    }
+    let c = PART.captures(text)?;
+    let n: u32 = c[1].parse().ok()?;
+    let total: u32 = c[2].parse().ok()?;
+    (total >= 2 && (1..=total).contains(&n)).then_some((n, total))
+}
+
+/// Merges consecutive posts of the same author numbered like "1/3", "2/3"
+/// and posted within `MULTIPART_WINDOW` of each other into the first part,
+/// as long as the result has at most `max_images`.
+pub fn merge_multipart(pics: Vec<Pic>, max_images: usize) -> Vec<Pic> {
+    let mut r: Vec<Pic> = Vec::with_capacity(pics.len());
+    let mut group: Vec<(u32, Pic)> = Vec::new();
+    let flush = |group: &mut Vec<(u32, Pic)>, r: &mut Vec<Pic>| {
+        group.sort_by_key(|(n, _)| *n);
+        let mut parts = group.drain(..).map(|(_, pic)| pic);
+        if let Some(mut first) = parts.next() {
+            for pic in parts {
+                first.images.extend(pic.images);
+                first.videos.extend(pic.videos);
+                first.alt_texts.extend(pic.alt_texts);
+                if !pic.text.is_empty() {
+                    first.text.push('\n');
+                    first.text.push_str(&pic.text);
+                }
+                first.comments.hot.extend(pic.comments.hot);
+                first.comments.mentioned.extend(pic.comments.mentioned);
+                first.parts.push(pic.id);
+            }
+            r.push(first);
+        }
+    };
+    for pic in pics {
+        let part = part_number(&pic.text);
+        let joins_group = match (part, group.first()) {
+            (Some((n, total)), Some((_, head))) => {
+                head.section == pic.section
+                    && head.author == pic.author
+                    && part_number(&head.text).map(|(_, t)| t) == Some(total)
+                    && group.iter().all(|(m, _)| *m != n)
+                    && close_in_time(&group.last().expect("never").1, &pic)
+                    && group.iter().map(|(_, p)| p.images.len()).sum::<usize>() + pic.images.len()
+                        <= max_images
+            }
+            _ => false,
+        };
+        if !joins_group {
+            flush(&mut group, &mut r);
+        }
+        match part {
+            Some((n, _)) => group.push((n, pic)),
+            None => r.push(pic),
+        }
+    }
+    flush(&mut group, &mut r);
+    r
+}
+
+/// Posts without a known time aren't kept apart
+fn close_in_time(a: &Pic, b: &Pic) -> bool {
+    match (a.published_at, b.published_at) {
+        (Some(a), Some(b)) => a.abs_diff(b) <= MULTIPART_WINDOW,
+        _ => true,
+    }
+}
+
 macro_rules! pos {
     () => {
         concat!(file!(), ": ", line!(), ",", column!())
@@ -307,52 +607,98 @@ mod selector {
     use lazy_static::lazy_static;
     use scraper::Selector;
     lazy_static! {
-        pub static ref AUTHOR: Selector = Selector::parse("#list-pic .acv_author").unwrap();
-        pub static ref COMMENT: Selector = Selector::parse("#list-pic .acv_comment").unwrap();
         pub static ref COMMENT_IMG: Selector = Selector::parse(".view_img_link").unwrap();
-        pub static ref VOTE: Selector = Selector::parse("#list-pic .jandan-vote").unwrap();
+        pub static ref IMG: Selector = Selector::parse("img").unwrap();
+        pub static ref VIDEO: Selector = Selector::parse("video[src], video source[src]").unwrap();
         pub static ref ID: Selector = Selector::parse("a[data-id]").unwrap();
         pub static ref HREF: Selector = Selector::parse("*[href]").unwrap();
+        pub static ref AUTHOR_LINK: Selector =
+            Selector::parse("a[href*='/author/'], a[href*='/member/']").unwrap();
         pub static ref P: Selector = Selector::parse("p").unwrap();
         pub static ref SPAN: Selector = Selector::parse("span").unwrap();
     }
 }
 
-pub async fn do_the_evil() -> anyhow::Result<Vec<Pic>> {
-    let html = CLIENT
-        .with(|client| client.get(JANDAN_HOME))
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
+async fn get_page(endpoint: &Endpoint, path: &str) -> Result<String, SpiderError> {
+    Ok(endpoint.get(path).await?.error_for_status()?.text().await?)
+}
 
-    let document = Html::parse_document(&html);
+/// Post lists are made of parallel author, content and vote blocks
+struct List {
+    author: Selector,
+    comment: Selector,
+    vote: Selector,
+}
 
-    let mut pics = Vec::new();
+impl List {
+    fn new(root: &str, author: &str, comment: &str) -> Result<Self, SpiderError> {
+        let parse = |class: &str| {
+            Selector::parse(&format!("{} {}", root, class))
+                .map_err(|_| SpiderError::Parse(format!("invalid selector {} {}", root, class)))
+        };
+        Ok(List {
+            author: parse(author)?,
+            comment: parse(comment)?,
+            vote: parse(".jandan-vote")?,
+        })
+    }
+
+    /// The newest posts of `section` on the front page
+    fn front_page(section: &str) -> Result<Self, SpiderError> {
+        Self::new(&format!("#list-{}", section), ".acv_author", ".acv_comment")
+    }
 
+    /// The pages of the section itself, `<section>/page-<n>`
+    fn section_page() -> Result<Self, SpiderError> {
+        Self::new("#comments .commentlist", ".author", ".text")
+    }
+}
+
+fn layout(pos: &'static str) -> SpiderError {
+    SpiderError::Layout(Cow::Borrowed(pos))
+}
+
+/// The posts of `list` in `document`, leaving out the IDs `skip` accepts.
+/// Their comments are left empty, see `load_comments`.
+fn parse_list(
+    document: &Html,
+    list: &List,
+    section: &str,
+    skip: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Pic>, SpiderError> {
+    let mut pics = Vec::new();
     for ((author_div, comment_div), vote_div) in document
-        .select(&selector::AUTHOR)
-        .zip(document.select(&selector::COMMENT))
-        .zip(document.select(&selector::VOTE))
+        .select(&list.author)
+        .zip(document.select(&list.comment))
+        .zip(document.select(&list.vote))
     {
         let author = author_div
             .text()
             .next()
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .split('@')
             .next()
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .trim()
             .to_owned();
+        let published_at = post_age(&author_div.text().collect::<String>())
+            .map(|age| crate::database::now().saturating_sub(age));
         let link = author_div
             .select(&selector::HREF)
             .next()
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .value()
             .attr("href")
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .to_owned();
+        let author_link = author_div
+            .select(&selector::AUTHOR_LINK)
+            .next()
+            .and_then(|a| a.value().attr("href"))
+            .map(|href| match href.strip_prefix('/') {
+                Some(path) if !path.starts_with('/') => format!("{}{}", JANDAN_HOME, path),
+                _ => fix_scheme(href).into_owned(),
+            });
         let text_buf = comment_div
             .select(&selector::P)
             .flat_map(|p| p.children())
@@ -360,38 +706,67 @@ pub async fn do_the_evil() -> anyhow::Result<Vec<Pic>> {
             .map(|text| text.text.trim_matches('\n'))
             .filter(|line| !line.is_empty())
             .intersperse("\n")
-            .map(|line| Unescape::new(line.as_bytes().iter().copied()))
-            .flatten()
+            .flat_map(|line| Unescape::new(line.as_bytes().iter().copied()))
             .collect::<Vec<u8>>();
-        let text = String::from_utf8(text_buf).unwrap();
+        let (text, via) = split_via(&String::from_utf8(text_buf).unwrap());
         let images = comment_div
             .select(&selector::COMMENT_IMG)
-            .map(|a| a.value().attr("href").expect(pos!()))
-            .map(|href| fix_scheme(href).into_owned())
-            .collect::<Vec<String>>();
-        let mut votes = vote_div
-            .select(&selector::SPAN)
-            .map(|span| span.text().next().expect(pos!()))
-            .map(|vote_str| vote_str.parse::<u32>().expect(pos!()));
-        let (oo, xx) = (votes.next().expect(pos!()), votes.next().expect(pos!()));
+            .map(|a| a.value().attr("href").ok_or(layout(pos!())))
+            .map(|href| href.map(|href| fix_scheme(href).into_owned()))
+            .collect::<Result<Vec<String>, _>>()?;
+        let mut videos: Vec<String> = Vec::new();
+        for video in comment_div.select(&selector::VIDEO) {
+            let src = fix_scheme(video.value().attr("src").ok_or(layout(pos!()))?).into_owned();
+            if !videos.contains(&src) {
+                videos.push(src);
+            }
+        }
+        let mut alt_texts: Vec<String> = Vec::new();
+        for img in comment_div.select(&selector::IMG) {
+            for attr in ["alt", "title"] {
+                match img.value().attr(attr).map(str::trim) {
+                    Some(s) if !s.is_empty() && !alt_texts.iter().any(|t| t == s) => {
+                        alt_texts.push(s.to_owned())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut votes = vote_div.select(&selector::SPAN).map(|span| {
+            let vote_str = span.text().next().ok_or(layout(pos!()))?;
+            vote_str
+                .parse::<u32>()
+                .map_err(|e| SpiderError::Parse(format!("vote {:?}: {}", vote_str, e)))
+        });
+        let oo = votes.next().ok_or(layout(pos!()))??;
+        let xx = votes.next().ok_or(layout(pos!()))??;
         let id = vote_div
             .select(&selector::ID)
             .next()
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .value()
             .attr("data-id")
-            .expect(pos!())
+            .ok_or(layout(pos!()))?
             .to_string();
-        let comments = get_comments(&id).await?;
+        if skip(&id) {
+            continue;
+        }
         let pic = Pic {
+            section: section.to_owned(),
             author,
+            author_link,
             link,
             id,
             oo,
             xx,
+            published_at,
             text,
+            via,
             images,
-            comments,
+            videos,
+            alt_texts,
+            comments: Comments::default(),
+            parts: Vec::new(),
         };
         pics.push(pic);
     }
@@ -399,13 +774,294 @@ pub async fn do_the_evil() -> anyhow::Result<Vec<Pic>> {
     Ok(pics)
 }
 
+/// Fetches the tucao of `pic`
+pub async fn load_comments(endpoint: &Endpoint, pic: &mut Pic) -> Result<(), SpiderError> {
+    pic.comments = get_comments(endpoint, &pic.id).await?;
+    Ok(())
+}
+
+/// Goes through up to `max_pages` pages of `section`, newest first, until
+/// one has a post that was `seen` already. Posts in `known` or `seen` are
+/// left out, the rest come oldest first.
+async fn walk_pages(
+    endpoint: &Endpoint,
+    section: &str,
+    max_pages: usize,
+    known: &[Pic],
+    seen: &dyn Fn(&str, &str) -> bool,
+) -> Result<Vec<Pic>, SpiderError> {
+    let mut pics: Vec<Pic> = Vec::new();
+    let mut path = section.to_owned();
+    for _ in 0..max_pages {
+        let document = Html::parse_document(&get_page(endpoint, &path).await?);
+        let done = Cell::new(false);
+        let skip = |id: &str| {
+            let old = seen(section, id);
+            done.set(done.get() || old);
+            old || known.iter().chain(&pics).any(|pic| pic.id == id)
+        };
+        let (found, older) = parse_section_page(&document, section, &skip)?;
+        pics.extend(found);
+        match older {
+            Some(n) if !done.get() => path = format!("{}/page-{}", section, n),
+            _ => break,
+        }
+    }
+    pics.sort_by_key(|pic| pic.id.parse::<u64>().unwrap_or_default());
+    Ok(pics)
+}
+
+/// The posts on a page of `section` that `skip` leaves, and the number of
+/// the next older page if there is one
+fn parse_section_page(
+    document: &Html,
+    section: &str,
+    skip: &dyn Fn(&str) -> bool,
+) -> Result<(Vec<Pic>, Option<u32>), SpiderError> {
+    lazy_static! {
+        static ref CURRENT_PAGE: Selector = Selector::parse(".current-comment-page").unwrap();
+    }
+    let page: Option<u32> = document
+        .select(&CURRENT_PAGE)
+        .next()
+        .and_then(|e| e.text().next())
+        .and_then(|t| t.trim().trim_matches(|c| c == '[' || c == ']').parse().ok());
+    let pics = parse_list(document, &List::section_page()?, section, skip)?;
+    Ok((pics, page.filter(|&n| n > 1).map(|n| n - 1)))
+}
+
+/// Somewhere posts come from. `seen` tells whether a post of a section is
+/// in history already, so sources that page through older posts know when
+/// to stop.
+pub trait Source {
+    /// Posts come without comments, most of them are in history already
+    fn posts<'a>(
+        &'a mut self,
+        seen: &'a dyn Fn(&str, &str) -> bool,
+    ) -> LocalBoxStream<'a, Result<Pic, SpiderError>>;
+
+    fn load_comments<'a>(&'a self, pic: &'a mut Pic)
+        -> LocalBoxFuture<'a, Result<(), SpiderError>>;
+
+    /// A single post with its comments, wherever it is listed
+    fn post<'a>(&'a self, id: u64) -> LocalBoxFuture<'a, Result<Pic, SpiderError>>;
+
+    /// Where the last `posts` were fetched from, for the run info
+    fn origin(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The lists on jandan, through whichever of `endpoints` answers
+pub struct Jandan {
+    pub endpoints: Vec<Endpoint>,
+    pub sections: Vec<String>,
+    pub max_pages: usize,
+    answered: Option<Endpoint>,
+}
+
+impl Jandan {
+    pub fn new(endpoints: Vec<Endpoint>, sections: Vec<String>, max_pages: usize) -> Self {
+        Jandan {
+            endpoints,
+            sections,
+            max_pages,
+            answered: None,
+        }
+    }
+}
+
+impl Source for Jandan {
+    fn posts<'a>(
+        &'a mut self,
+        seen: &'a dyn Fn(&str, &str) -> bool,
+    ) -> LocalBoxStream<'a, Result<Pic, SpiderError>> {
+        stream::once(async move {
+            let (pics, endpoint) =
+                do_the_evil(&self.endpoints, &self.sections, self.max_pages, seen).await?;
+            self.answered = Some(endpoint);
+            Ok::<_, SpiderError>(stream::iter(pics.into_iter().map(Ok)))
+        })
+        .try_flatten()
+        .boxed_local()
+    }
+
+    fn load_comments<'a>(
+        &'a self,
+        pic: &'a mut Pic,
+    ) -> LocalBoxFuture<'a, Result<(), SpiderError>> {
+        async move {
+            let default = Endpoint::default();
+            let endpoint = self.answered.as_ref().unwrap_or(&default);
+            load_comments(endpoint, pic).await
+        }
+        .boxed_local()
+    }
+
+    fn post<'a>(&'a self, id: u64) -> LocalBoxFuture<'a, Result<Pic, SpiderError>> {
+        async move {
+            let default = Endpoint::default();
+            let endpoint = self.answered.as_ref().unwrap_or(&default);
+            fetch_post(endpoint, id).await
+        }
+        .boxed_local()
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.answered.as_ref().map(|e| e.base.clone())
+    }
+}
+
+/// Scrapes the post `id` from its own page, `t/<id>`, comments included. The
+/// page doesn't tell which list the post is in, `section` is left at
+/// [`SECTION`].
+pub async fn fetch_post(endpoint: &Endpoint, id: u64) -> Result<Pic, SpiderError> {
+    let resp = endpoint.get(&format!("t/{}", id)).await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(SpiderError::Gone);
+    }
+    let document = Html::parse_document(&resp.error_for_status()?.text().await?);
+    let id = id.to_string();
+    let mut pic = parse_list(&document, &List::section_page()?, SECTION, &|other| {
+        other != id
+    })?
+    .pop()
+    .ok_or_else(|| SpiderError::Layout(format!("post {} is not on its own page", id).into()))?;
+    load_comments(endpoint, &mut pic).await?;
+    Ok(pic)
+}
+
+/// Scrapes the front page lists of `sections`, and up to `max_pages` older
+/// pages of a section when none of its front page posts were `seen`. Tries
+/// `endpoints` in order, jandan.net if there are none. Returns the posts
+/// along with the endpoint that answered.
+pub async fn do_the_evil(
+    endpoints: &[Endpoint],
+    sections: &[String],
+    max_pages: usize,
+    seen: &dyn Fn(&str, &str) -> bool,
+) -> Result<(Vec<Pic>, Endpoint), SpiderError> {
+    let default = [Endpoint::default()];
+    let endpoints = if endpoints.is_empty() {
+        &default[..]
+    } else {
+        endpoints
+    };
+    let mut home = Err(SpiderError::Http(anyhow::anyhow!("no jandan endpoint")));
+    for endpoint in endpoints {
+        match get_page(endpoint, "").await {
+            Ok(html) => {
+                home = Ok((html, endpoint.clone()));
+                break;
+            }
+            Err(e) => {
+                warn!("{}: {:#}", endpoint.base, e);
+                home = Err(e);
+            }
+        }
+    }
+    // each failure was logged above, the last one stands for all of them
+    let (html, endpoint) = home?;
+
+    let document = Html::parse_document(&html);
+
+    let mut pics = Vec::new();
+
+    for section in sections {
+        let mut found = parse_list(&document, &List::front_page(section)?, section, &|_| false)?;
+        // missed runs leave posts that already dropped off the front page
+        if max_pages > 0 && !found.iter().any(|pic| seen(section, &pic.id)) {
+            // they were missed first, they go out first
+            let older = walk_pages(&endpoint, section, max_pages, &found, seen).await?;
+            found.splice(0..0, older);
+        }
+        pics.extend(found);
+    }
+
+    Ok((pics, endpoint))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::path::Path;
+
     #[tokio::test]
     #[ignore]
     async fn test() {
-        dbg!(do_the_evil().await.unwrap());
+        dbg!(
+            do_the_evil(&[], &[SECTION.to_owned()], 0, &|_, _| false)
+                .await
+                .unwrap()
+                .0
+        );
+    }
+
+    #[test]
+    fn part_numbers() {
+        assert_eq!(part_number("第 2/3 张"), Some((2, 3)));
+        assert_eq!(part_number("1 / 2"), Some((1, 2)));
+        assert_eq!(part_number("2021/10/1"), None);
+        assert_eq!(part_number("4/3"), None);
+    }
+
+    #[test]
+    fn multipart_window() {
+        let part = |id: &str, text: &str, published_at| Pic {
+            section: SECTION.into(),
+            author: "a".into(),
+            author_link: None,
+            link: String::new(),
+            id: id.into(),
+            oo: 0,
+            xx: 0,
+            published_at: Some(published_at),
+            text: text.into(),
+            via: None,
+            images: vec![format!("{}.jpg", id)],
+            videos: Vec::new(),
+            alt_texts: Vec::new(),
+            comments: Comments::default(),
+            parts: Vec::new(),
+        };
+        let merged = merge_multipart(
+            vec![
+                part("1", "1/2", 10_000),
+                part("2", "2/2", 10_000 + MULTIPART_WINDOW),
+            ],
+            9,
+        );
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].parts, vec!["2"]);
+        let apart = merge_multipart(
+            vec![
+                part("1", "1/2", 10_000),
+                part("2", "2/2", 10_000 + MULTIPART_WINDOW + 1),
+            ],
+            9,
+        );
+        assert_eq!(apart.len(), 2);
+    }
+
+    #[test]
+    fn via_lines() {
+        assert_eq!(
+            split_via("好图\nvia: https://weibo.com/a\n再来"),
+            ("好图\n再来".into(), Some("https://weibo.com/a".into()))
+        );
+        assert_eq!(
+            split_via("来源：微博 @someone"),
+            (String::new(), Some("微博 @someone".into()))
+        );
+        assert_eq!(split_via("viaduct"), ("viaduct".into(), None));
+    }
+
+    #[test]
+    fn post_ages() {
+        assert_eq!(post_age("name @3 hours ago"), Some(3 * 60 * 60));
+        assert_eq!(post_age("name@ 2天 ago"), Some(2 * 24 * 60 * 60));
+        assert_eq!(post_age("name @1 min ago"), Some(60));
+        assert_eq!(post_age("name"), None);
     }
 
     #[test]
@@ -427,4 +1083,162 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn layout_changes() {
+        let post = |votes: &str| {
+            Html::parse_document(&format!(
+                r#"<div id="comments"><ol class="commentlist"><li>
+                <div class="author"><strong>name</strong><a href="/t/1">#</a></div>
+                <div class="text"><p>text</p></div>
+                <div class="jandan-vote">{}<a data-id="1"></a></div>
+                </li></ol></div>"#,
+                votes
+            ))
+        };
+        let list = List::section_page().unwrap();
+        let pics = parse_list(
+            &post("<span>2</span><span>1</span>"),
+            &list,
+            SECTION,
+            &|_| false,
+        );
+        assert_eq!(pics.unwrap()[0].oo, 2);
+        let pics = parse_list(&post(""), &list, SECTION, &|_| false);
+        assert!(matches!(pics, Err(SpiderError::Layout(_))));
+        let pics = parse_list(
+            &post("<span>2</span><span>?</span>"),
+            &list,
+            SECTION,
+            &|_| false,
+        );
+        assert!(matches!(pics, Err(SpiderError::Parse(_))));
+    }
+
+    #[test]
+    fn section_page_fixture() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/pages/pic.html");
+        let document = Html::parse_document(&std::fs::read_to_string(path).unwrap());
+        let (pics, older) = parse_section_page(&document, SECTION, &|id| id == "5002").unwrap();
+        assert_eq!(older, Some(40));
+        let ids: Vec<&str> = pics.iter().map(|pic| &*pic.id).collect();
+        assert_eq!(ids, ["5003", "5001"]);
+        let pic = &pics[0];
+        assert_eq!(pic.author, "sun");
+        assert_eq!(pic.link, "/t/5003");
+        assert_eq!((pic.oo, pic.xx), (12, 3));
+        assert_eq!(pic.text, "第一张");
+        assert_eq!(pic.images, ["https://wx1.sinaimg.cn/large/a.jpg"]);
+
+        let first = Html::parse_document(r#"<span class="current-comment-page">[1]</span>"#);
+        assert_eq!(
+            parse_section_page(&first, SECTION, &|_| false).unwrap().1,
+            None
+        );
+    }
+
+    #[test]
+    fn post_text_entities() {
+        // jandan escapes the post text once more on top of the HTML
+        let document = Html::parse_document(
+            r#"<div id="comments"><ol class="commentlist"><li>
+            <div class="author"><strong>name</strong><a href="/t/1">#</a></div>
+            <div class="text"><p>Tom &amp;amp; Jerry &amp;quot;&lt;3&quot;</p></div>
+            <div class="jandan-vote"><span>1</span><span>0</span><a data-id="1"></a></div>
+            </li></ol></div>"#,
+        );
+        let list = List::section_page().unwrap();
+        let pics = parse_list(&document, &list, SECTION, &|_| false).unwrap();
+        assert_eq!(pics[0].text, r#"Tom & Jerry "<3""#);
+    }
+
+    #[test]
+    fn markup() {
+        let s = concat!(
+            r##"<b>bold <i>still bold</i></b><em>italic</em> <a data-id="1" href="#tucao-1">@a</a>"##,
+            r#"<br><a href="//jandan.net/t/1">link</a><a href="/t/2"><img src="link" /></a>"#,
+        );
+        let r = parse_comment(s.to_string());
+        let r = r.entities().collect::<Vec<_>>();
+        use TextEntity::*;
+        assert_eq!(
+            r,
+            vec![
+                Bold("bold still bold"),
+                Italic("italic"),
+                Text(" "),
+                Mention { name: "@a", id: 1 },
+                Br,
+                Link {
+                    text: "link",
+                    url: "https://jandan.net/t/1"
+                },
+                Img("link"),
+            ]
+        );
+
+        let r = parse_comment(r#"<a data-id="99999999999999999999">@a</a> &lt;3"#.to_string());
+        assert_eq!(r.entities().collect::<Vec<_>>(), vec![Text("@a <3")]);
+
+        // nothing between or after two mentions on a line goes missing
+        let r = parse_comment(
+            r##"<a href="#tucao-1" data-id="1">@a</a> and <a href="#tucao-2" data-id="2">@b</a> too"##
+                .to_string(),
+        );
+        assert_eq!(
+            r.entities().collect::<Vec<_>>(),
+            vec![
+                Mention { name: "@a", id: 1 },
+                Text(" and "),
+                Mention { name: "@b", id: 2 },
+                Text(" too"),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_mentions() {
+        let comment = |id, mentions: Vec<u64>| Comment {
+            id,
+            author: String::new(),
+            oo: 0,
+            xx: 0,
+            content: parse_comment(String::new()),
+            mentions,
+            date: None,
+        };
+        let hot = vec![comment(1, vec![2])];
+        let mut tucao = HashMap::new();
+        assert!(missing_mention(&hot, &tucao));
+        tucao.insert(2, comment(2, vec![3]));
+        assert!(missing_mention(&hot, &tucao));
+        tucao.insert(3, comment(3, vec![2]));
+        assert!(!missing_mention(&hot, &tucao));
+    }
+
+    #[test]
+    fn comment_order() {
+        let comment = |id, oo, xx, date| Comment {
+            id,
+            author: String::new(),
+            oo,
+            xx,
+            content: parse_comment(String::new()),
+            mentions: Vec::new(),
+            date,
+        };
+        let now = 100 * 3600;
+        let mut comments = vec![
+            comment(1, 10, 0, Some(0)),
+            comment(2, 5, 0, Some(now)),
+            comment(3, 20, 14, None),
+        ];
+        let ids = |c: &[Comment]| c.iter().map(|c| c.id).collect::<Vec<_>>();
+        CommentOrder::Api.sort(&mut comments, now);
+        assert_eq!(ids(&comments), [1, 2, 3]);
+        CommentOrder::Votes.sort(&mut comments, now);
+        assert_eq!(ids(&comments), [1, 3, 2]);
+        CommentOrder::VotesByAge.sort(&mut comments, now);
+        assert_eq!(ids(&comments), [3, 2, 1]);
+    }
 }
@@ -0,0 +1,90 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use tbot::types::parameters::ChatId;
+
+/// https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this
+const GLOBAL_PER_SECOND: usize = 30;
+const CHAT_PER_MINUTE: usize = 20;
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref SENT: Mutex<Sent> = Mutex::new(Sent::default());
+}
+
+/// When recent messages went out, including reserved future slots
+#[derive(Default)]
+struct Sent {
+    global: VecDeque<Instant>,
+    chats: HashMap<String, VecDeque<Instant>>,
+    /// Chat of the latest call
+    last: Option<String>,
+}
+
+/// Earliest time `n` more messages fit, given the last `limit` in `window`
+fn next_slot(
+    sent: &VecDeque<Instant>,
+    limit: usize,
+    window: Duration,
+    n: usize,
+) -> Option<Instant> {
+    // the message that has to leave the window before the n-th new one fits
+    let i = (sent.len() + n).checked_sub(limit + 1)?;
+    sent.get(i).map(|t| *t + window)
+}
+
+/// Waits until `messages` more messages can be sent to `chat` without
+/// hitting telegram's flood limits. Every call to telegram should go
+/// through here, the limits are per bot.
+pub async fn wait(chat: ChatId<'_>, messages: usize) {
+    let when = {
+        let mut sent = SENT.lock().unwrap();
+        let now = Instant::now();
+        let Sent {
+            global,
+            chats,
+            last,
+        } = &mut *sent;
+        *last = Some(match chat {
+            ChatId::Id(id) => id.0.to_string(),
+            ChatId::Username(name) => name.to_owned(),
+            _ => format!("{:?}", chat),
+        });
+        let chat = chats.entry(format!("{:?}", chat)).or_default();
+        while global
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > Duration::from_secs(1))
+        {
+            global.pop_front();
+        }
+        while chat
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > Duration::from_secs(60))
+        {
+            chat.pop_front();
+        }
+        let when = [
+            Some(now),
+            next_slot(global, GLOBAL_PER_SECOND, Duration::from_secs(1), messages),
+            next_slot(chat, CHAT_PER_MINUTE, Duration::from_secs(60), messages),
+            chat.back().map(|t| *t + CHAT_INTERVAL),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .expect("now is always there");
+        for _ in 0..messages {
+            global.push_back(when);
+            chat.push_back(when);
+        }
+        when
+    };
+    tokio::time::delay_until(tokio::time::Instant::from_std(when)).await;
+}
+
+/// The chat of the latest `wait`, so the one a failed call was made to
+pub fn last_chat() -> Option<String> {
+    SENT.lock().unwrap().last.clone()
+}
@@ -0,0 +1,121 @@
+use anyhow::Context;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use tbot::types::parameters::ChatId;
+
+use crate::database::now;
+use crate::http::CLIENT;
+use crate::throttle;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// GitHub allows 60 unauthenticated requests an hour, once a day is plenty
+const INTERVAL: u64 = 24 * 60 * 60;
+const CHANGELOG_LINES: usize = 10;
+
+/// Tells `chat` when `repo`, `owner/name` on GitHub, has a newer release
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    pub repo: String,
+    pub chat: String,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct State {
+    pub checked_at: u64,
+    /// Tag of the release `chat` was last told about
+    pub notified: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+/// Looks for a new release at most once every `INTERVAL`, announcing each
+/// one only once
+pub async fn check(bot: &tbot::Bot, config: &Config, state: &mut State) -> anyhow::Result<()> {
+    if now().saturating_sub(state.checked_at) < INTERVAL {
+        return Ok(());
+    }
+    // a failed check waits for the next interval too, GitHub being down
+    // shouldn't cost a request every run
+    state.checked_at = now();
+    let url = format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        config.repo
+    );
+    let release: Release = CLIENT
+        .with(|client| client.get(&url))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| format!("read {}", url))?;
+    if !newer(&release.tag_name, VERSION) || state.notified.as_deref() == Some(&*release.tag_name) {
+        return Ok(());
+    }
+    throttle::wait(config.chat.as_str().into(), 1).await;
+    bot.send_message(ChatId::from(&*config.chat), announcement(&release).as_str())
+        .is_web_page_preview_disabled(true)
+        .call()
+        .await?;
+    state.notified = Some(release.tag_name);
+    Ok(())
+}
+
+fn announcement(release: &Release) -> String {
+    let mut text = format!(
+        "{} {} is out, this is {}\n{}",
+        env!("CARGO_PKG_NAME"),
+        release.tag_name,
+        VERSION,
+        release.html_url
+    );
+    let mut lines = release
+        .body
+        .as_deref()
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty());
+    let excerpt: Vec<&str> = lines.by_ref().take(CHANGELOG_LINES).collect();
+    if !excerpt.is_empty() {
+        text.push_str("\n\n");
+        text.push_str(&excerpt.join("\n"));
+        if lines.next().is_some() {
+            text.push_str("\n…");
+        }
+    }
+    text
+}
+
+/// Compares the numeric parts of versions like "v1.2.3"
+fn newer(tag: &str, current: &str) -> bool {
+    let parts = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-'])
+            .map_while(|p| p.parse().ok())
+            .collect()
+    };
+    parts(tag) > parts(current)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert!(newer("v0.3.0", "0.2.0"));
+        assert!(newer("0.2.10", "0.2.9"));
+        assert!(newer("v0.2.0.1", "0.2.0"));
+        assert!(!newer("v0.2.0", "0.2.0"));
+        assert!(!newer("0.1.9", "0.2.0"));
+        assert!(!newer("nightly", "0.2.0"));
+    }
+}
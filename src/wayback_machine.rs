@@ -1,4 +1,3 @@
-use std::borrow::Cow;
 use std::time::Duration;
 
 use reqwest::header;
@@ -12,7 +11,6 @@ pub struct UserStatusResp {
     pub available: usize,
     pub daily_captures: usize,
     pub daily_captures_limit: usize,
-    pub processing: usize,
 }
 
 #[derive(Serialize)]
@@ -28,24 +26,33 @@ pub struct SaveReq {
     pub skip_first_archive: bool,
 }
 
-pub async fn push(token: &str, imgs: &[Cow<'_, str>]) -> anyhow::Result<()> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::ACCEPT,
-        header::HeaderValue::from_static("application/json"),
-    );
-    headers.insert(
-        header::AUTHORIZATION,
-        format!("LOW {}", token).parse().unwrap(),
-    );
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(5))
-        .default_headers(headers)
-        .build()
-        .unwrap();
+pub struct Client {
+    client: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(token: &str) -> Self {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            header::HeaderValue::from_static("application/json"),
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("LOW {}", token).parse().unwrap(),
+        );
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        Client { client }
+    }
 
-    for img in imgs {
-        let status: UserStatusResp = client
+    /// Whether a capture can be started right now
+    pub async fn has_capacity(&self) -> anyhow::Result<bool> {
+        let status: UserStatusResp = self
+            .client
             .get(WM_USER_STATUS)
             .query(&[(
                 "_t",
@@ -53,19 +60,18 @@ pub async fn push(token: &str, imgs: &[Cow<'_, str>]) -> anyhow::Result<()> {
             )])
             .send()
             .await?
+            .error_for_status()?
             .json()
             .await?;
+        Ok(status.available > 0 && status.daily_captures < status.daily_captures_limit)
+    }
 
-        assert!(status.daily_captures < status.daily_captures_limit);
-
-        while status.available == 0 {
-            tokio::time::delay_for(Duration::from_secs(5)).await;
-        }
-
-        let req = client
+    pub async fn save(&self, url: &str) -> anyhow::Result<()> {
+        let req = self
+            .client
             .post(WM_SAVE)
             .form(&SaveReq {
-                url: format!("https://jandan.net/t/{}", img),
+                url: url.to_owned(),
                 capture_all: true,
                 capture_outlinks: false,
                 force_get: true,
@@ -73,9 +79,9 @@ pub async fn push(token: &str, imgs: &[Cow<'_, str>]) -> anyhow::Result<()> {
             })
             .build()?;
 
-        client.execute(req).await?;
+        self.client.execute(req).await?.error_for_status()?;
+        Ok(())
     }
-    Ok(())
 }
 
 fn ser_bool_as_int<S>(b: &bool, s: S) -> Result<S::Ok, S::Error>